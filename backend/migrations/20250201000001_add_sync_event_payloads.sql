@@ -0,0 +1,8 @@
+-- Cross-instance sync events too large to fit in a NOTIFY payload
+CREATE TABLE sync_event_payloads (
+    id BIGSERIAL PRIMARY KEY,
+    data BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_sync_event_payloads_created ON sync_event_payloads(created_at);
@@ -0,0 +1,9 @@
+-- document_updates becomes the append-only log of changes not yet merged into
+-- subdocs.yjs_state, so deleting a user must not delete document content
+ALTER TABLE document_updates ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE document_updates DROP CONSTRAINT document_updates_user_id_fkey;
+ALTER TABLE document_updates ADD CONSTRAINT document_updates_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
+
+-- Log entries are replayed in insertion order
+CREATE INDEX idx_updates_subdoc_id ON document_updates(subdoc_guid, id);
@@ -0,0 +1,12 @@
+-- What the sync server does with guids a vault has no document for:
+-- 'reject' answers doc_not_found, 'auto_create' saves a new document of the
+-- declared type under the declared parent on the first edit
+ALTER TABLE vaults
+    ADD COLUMN doc_creation_policy TEXT NOT NULL DEFAULT 'auto_create'
+        CHECK (doc_creation_policy IN ('reject', 'auto_create')),
+    ADD COLUMN auto_create_doc_type TEXT NOT NULL DEFAULT 'document'
+        CHECK (auto_create_doc_type IN ('document', 'canvas')),
+    ADD COLUMN auto_create_parent_guid TEXT REFERENCES subdocs(guid) ON DELETE SET NULL;
+
+-- Document creations are audited too, including those made outside a sync session
+ALTER TABLE document_edits ALTER COLUMN session_id DROP NOT NULL;
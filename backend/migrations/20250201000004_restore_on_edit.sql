@@ -0,0 +1,2 @@
+-- Whether an owner's edit to a document in the trash restores it instead of being refused
+ALTER TABLE vaults ADD COLUMN restore_on_edit BOOLEAN NOT NULL DEFAULT FALSE;
@@ -6,6 +6,7 @@ use axum::{
     routing::get,
 };
 use serde::Deserialize;
+use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::api::auth::AppState;
@@ -154,3 +155,34 @@ pub async fn get_document_snapshots(
 
     Ok(Json(snapshots))
 }
+
+/// Record the creation of a document in its edit history, with the state it was created
+/// with. `session_id` is the sync session that created it, `None` for the REST API.
+pub async fn log_document_created(
+    pool: &PgPool,
+    subdoc_guid: &str,
+    user_id: Uuid,
+    session_id: Option<Uuid>,
+    initial_state: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO document_edits (subdoc_guid, user_id, session_id, yjs_update, edit_type)
+        VALUES ($1, $2, $3, $4, 'create')
+        "#,
+        subdoc_guid,
+        user_id,
+        session_id,
+        initial_state
+    )
+    .execute(pool)
+    .await?;
+
+    tracing::info!(
+        "Logged creation: subdoc_guid={}, user_id={}, session_id={:?}",
+        subdoc_guid,
+        user_id,
+        session_id
+    );
+    Ok(())
+}
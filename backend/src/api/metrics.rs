@@ -0,0 +1,80 @@
+use crate::api::auth::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+
+/// Sync server metrics in the Prometheus text format
+///
+/// Scrapers present `METRICS_TOKEN` as a bearer token; without a configured token
+/// the endpoint does not exist.
+pub async fn metrics_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let Some(expected) = state.config.metrics_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|token| tokens_match(token, expected)) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let documents = state.sync_manager.active_documents().await;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.sync_manager.metrics().render(&documents),
+    ))
+}
+
+/// Compare without bailing out at the first differing byte
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SyncConfig;
+    use crate::test_support::app_state;
+    use axum::http::HeaderValue;
+    use sqlx::PgPool;
+
+    async fn scrape(state: &AppState, token: Option<&str>) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        match metrics_handler(State(state.clone()), headers).await {
+            Ok(response) => response.into_response().status(),
+            Err(status) => status,
+        }
+    }
+
+    #[sqlx::test]
+    async fn metrics_require_the_configured_token(pool: PgPool) {
+        let mut state = app_state(pool, SyncConfig::default());
+        assert_eq!(
+            scrape(&state, Some("anything")).await,
+            StatusCode::NOT_FOUND
+        );
+
+        state.config.metrics_token = Some("scrape-me".to_string());
+        assert_eq!(scrape(&state, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            scrape(&state, Some("scrape-you")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(scrape(&state, Some("scrape-me")).await, StatusCode::OK);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod audit;
 pub mod auth;
+mod metrics;
 pub mod organizations;
 pub mod uploads;
 pub mod users;
@@ -8,8 +9,9 @@ mod websocket;
 
 pub use audit::audit_routes;
 pub use auth::auth_routes;
+pub use metrics::metrics_handler;
 pub use organizations::organization_routes;
 pub use uploads::upload_routes;
 pub use users::user_routes;
 pub use vaults::vault_routes;
-pub use websocket::ws_handler;
+pub use websocket::{ws_handler, ws_multiplex_handler};
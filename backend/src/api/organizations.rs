@@ -11,6 +11,7 @@ use uuid::Uuid;
 use crate::api::auth::AppState;
 use crate::auth::jwt::Claims;
 use crate::models::{Organization, OrganizationMember, OrganizationMemberWithProfile};
+use crate::sync::AccessChange;
 
 pub fn organization_routes() -> Router<AppState> {
     Router::new()
@@ -347,6 +348,11 @@ pub async fn add_member(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::organization(org_id, member.user_id))
+        .await;
+
     Ok((StatusCode::CREATED, Json(member)))
 }
 
@@ -397,6 +403,11 @@ pub async fn update_member_role(
     })?
     .ok_or(StatusCode::NOT_FOUND)?;
 
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::organization(org_id, member.user_id))
+        .await;
+
     Ok(Json(member))
 }
 
@@ -423,15 +434,24 @@ pub async fn remove_member(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    sqlx::query("DELETE FROM organization_members WHERE id = $1 AND org_id = $2")
-        .bind(member_id)
-        .bind(org_id)
-        .execute(pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to remove org member: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+    let removed_user = sqlx::query_scalar::<_, Uuid>(
+        "DELETE FROM organization_members WHERE id = $1 AND org_id = $2 RETURNING user_id",
+    )
+    .bind(member_id)
+    .bind(org_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to remove org member: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if let Some(user_id) = removed_user {
+        state
+            .sync_manager
+            .notify_access_change(AccessChange::organization(org_id, user_id))
+            .await;
+    }
 
     Ok(StatusCode::NO_CONTENT)
 }
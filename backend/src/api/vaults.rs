@@ -2,18 +2,24 @@ use axum::{
     Json, Router,
     extract::{Path, State},
     http::StatusCode,
-    routing::{delete, get, post},
+    routing::{delete, get, patch, post},
 };
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::collections::{BTreeSet, HashMap};
 use uuid::Uuid;
 use yrs::updates::encoder::Encode;
 use yrs::{Map, ReadTxn, Transact, WriteTxn};
 
 use crate::{
-    api::auth::AppState,
+    api::{audit::log_document_created, auth::AppState},
     auth::jwt::Claims,
-    models::{DocumentMetadata, Vault, VaultMember, VaultMemberWithProfile},
+    cleanup::TRASH_RETENTION_DAYS,
+    models::{
+        DocCreationPolicy, DocumentMetadata, DocumentMetadataPatch, PublicUserProfile, Vault,
+        VaultMember, VaultMemberWithProfile, VaultSettings,
+    },
+    sync::{AccessChange, DocumentNotFound, InvalidMove, TrashChange},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -162,6 +168,21 @@ pub fn vault_routes() -> Router<AppState> {
             "/{vault_id}/documents/metadata",
             get(get_vault_documents_metadata),
         )
+        .route(
+            "/{vault_id}/documents/{guid}",
+            patch(update_document).delete(delete_document),
+        )
+        .route(
+            "/{vault_id}/documents/{guid}/restore",
+            post(restore_document),
+        )
+        .route("/{vault_id}/documents/{guid}/move", post(move_document))
+        .route("/{vault_id}/trash", get(list_deleted_documents))
+        .route("/{vault_id}/presence", get(get_vault_presence))
+        .route(
+            "/{vault_id}/settings",
+            get(get_vault_settings).put(update_vault_settings),
+        )
         .route(
             "/{vault_id}/members",
             get(list_vault_members).post(add_vault_member),
@@ -300,24 +321,51 @@ async fn delete_vault(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    // One transaction, so the vault and its documents share the deletion time that
+    // restore_vault tells them apart from documents trashed on their own by
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     sqlx::query(
         "UPDATE vaults SET deleted_at = NOW(), deleted_by = $1 WHERE id = $2 AND deleted_at IS NULL",
     )
     .bind(claims.sub)
     .bind(vault_id)
-    .execute(&state.pool)
+    .execute(&mut *tx)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    sqlx::query(
-        "UPDATE subdocs SET deleted_at = NOW(), deleted_by = $1 WHERE vault_id = $2 AND deleted_at IS NULL",
+    let trashed = sqlx::query_scalar::<_, String>(
+        "UPDATE subdocs SET deleted_at = NOW(), deleted_by = $1 WHERE vault_id = $2 AND deleted_at IS NULL RETURNING guid",
     )
     .bind(claims.sub)
     .bind(vault_id)
-    .execute(&state.pool)
+    .fetch_all(&mut *tx)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state
+        .sync_manager
+        .notify_trash_change(
+            &trashed,
+            TrashChange {
+                vault_id,
+                deleted: true,
+            },
+        )
+        .await;
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::vault(vault_id, None))
+        .await;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -404,6 +452,12 @@ async fn transfer_vault_to_org(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    // The previous owner may only have access through the new org now
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::vault(vault_id, None))
+        .await;
+
     tracing::info!(
         "Successfully transferred vault {} to org {}",
         vault_id,
@@ -479,6 +533,10 @@ async fn create_document(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    log_document_created(&state.pool, &guid, claims.sub, None, &yjs_state)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     let created_at = chrono::Utc::now();
 
     Ok(Json(CreateDocumentResponse {
@@ -551,6 +609,10 @@ async fn create_canvas(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    log_document_created(&state.pool, &guid, claims.sub, None, &yjs_state)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     let created_at = chrono::Utc::now();
 
     Ok(Json(CreateDocumentResponse {
@@ -588,7 +650,8 @@ async fn get_vault_documents_metadata(
 
     let documents = sqlx::query!(
         "SELECT s.guid, s.vault_id, s.doc_type, s.parent_guid, s.created_at, s.modified_at,
-                m.title, m.icon, m.description, m.tags
+                m.title, m.icon, m.description, m.tags,
+                COALESCE(m.extra, '{}') as \"extra!\"
          FROM subdocs s
          LEFT JOIN subdoc_metadata m ON s.guid = m.subdoc_guid
          WHERE s.vault_id = $1 AND s.deleted_at IS NULL
@@ -609,6 +672,7 @@ async fn get_vault_documents_metadata(
             icon: doc.icon.clone(),
             description: doc.description.clone(),
             tags: doc.tags.clone().unwrap_or_default(),
+            extra: doc.extra.clone(),
             parent_guid: doc.parent_guid.clone(),
             created_at: doc.created_at,
             modified_at: doc.modified_at,
@@ -618,6 +682,472 @@ async fn get_vault_documents_metadata(
     Ok(Json(metadata))
 }
 
+/// Let owners and editors through; the vault does not exist for anyone without a role
+async fn require_editor(pool: &PgPool, vault_id: Uuid, user_id: Uuid) -> Result<(), StatusCode> {
+    let role = get_user_vault_role(pool, vault_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match role {
+        VaultRole::None => Err(StatusCode::NOT_FOUND),
+        VaultRole::Viewer => Err(StatusCode::FORBIDDEN),
+        VaultRole::Owner | VaultRole::Editor => Ok(()),
+    }
+}
+
+fn document_error(error: anyhow::Error) -> StatusCode {
+    if error.is::<DocumentNotFound>() {
+        StatusCode::NOT_FOUND
+    } else if let Some(invalid) = error.downcast_ref::<InvalidMove>() {
+        match invalid {
+            InvalidMove::ParentNotFound(_) => StatusCode::BAD_REQUEST,
+            InvalidMove::Cycle { .. } => StatusCode::CONFLICT,
+        }
+    } else {
+        tracing::error!("Document operation failed: {:?}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Rename a document or change its icon, description, tags or extra settings
+async fn update_document(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((vault_id, guid)): Path<(Uuid, String)>,
+    Json(patch): Json<DocumentMetadataPatch>,
+) -> Result<Json<DocumentMetadata>, StatusCode> {
+    require_editor(&state.pool, vault_id, claims.sub).await?;
+
+    let metadata = state
+        .sync_manager
+        .update_metadata(&guid, vault_id, &patch)
+        .await
+        .map_err(document_error)?;
+
+    Ok(Json(metadata))
+}
+
+/// Move a document to the trash, along with every document nested under it
+async fn delete_document(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((vault_id, guid)): Path<(Uuid, String)>,
+) -> Result<StatusCode, StatusCode> {
+    require_editor(&state.pool, vault_id, claims.sub).await?;
+
+    // Holds off moves in the vault (see persistence::move_document) until the whole
+    // tree is in the trash, so no document ends up live under a trashed parent
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query!(
+        "SELECT id FROM vaults WHERE id = $1 FOR NO KEY UPDATE",
+        vault_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let tree = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE tree AS (
+            SELECT guid FROM subdocs
+            WHERE guid = $1 AND vault_id = $2 AND deleted_at IS NULL
+            UNION
+            SELECT s.guid FROM subdocs s
+            JOIN tree t ON s.parent_guid = t.guid
+            WHERE s.vault_id = $2 AND s.deleted_at IS NULL
+        )
+        SELECT guid AS "guid!" FROM tree
+        "#,
+        guid,
+        vault_id
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if tree.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Save edits still held in memory, so the trash has the latest state
+    for guid in &tree {
+        if let Err(e) = state.sync_manager.flush(guid).await {
+            tracing::error!(
+                "Failed to flush document {} before deleting it: {:?}",
+                guid,
+                e
+            );
+        }
+    }
+
+    let trashed = sqlx::query_scalar!(
+        "UPDATE subdocs SET deleted_at = NOW(), deleted_by = $2
+         WHERE guid = ANY($1) AND deleted_at IS NULL
+         RETURNING guid",
+        &tree,
+        claims.sub
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state
+        .sync_manager
+        .notify_trash_change(
+            &trashed,
+            TrashChange {
+                vault_id,
+                deleted: true,
+            },
+        )
+        .await;
+
+    tracing::info!(
+        "User {} moved document {} and {} nested documents of vault {} to the trash",
+        claims.sub,
+        guid,
+        trashed.len().saturating_sub(1),
+        vault_id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Take a document out of the trash, along with the nested documents deleted with it
+async fn restore_document(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((vault_id, guid)): Path<(Uuid, String)>,
+) -> Result<StatusCode, StatusCode> {
+    require_editor(&state.pool, vault_id, claims.sub).await?;
+
+    let document = sqlx::query!(
+        r#"
+        SELECT s.deleted_at AS "deleted_at!", p.deleted_at IS NOT NULL AS "parent_deleted!"
+        FROM subdocs s
+        LEFT JOIN subdocs p ON p.guid = s.parent_guid
+        WHERE s.guid = $1 AND s.vault_id = $2 AND s.deleted_at IS NOT NULL
+        "#,
+        guid,
+        vault_id
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // The parent has to come back first
+    if document.parent_deleted {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // Documents trashed separately, before or after, stay in the trash
+    let restored = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE tree AS (
+            SELECT guid FROM subdocs WHERE guid = $1
+            UNION
+            SELECT s.guid FROM subdocs s
+            JOIN tree t ON s.parent_guid = t.guid
+            WHERE s.deleted_at = $2
+        )
+        UPDATE subdocs SET deleted_at = NULL, deleted_by = NULL
+        WHERE guid IN (SELECT guid FROM tree) AND deleted_at = $2
+        RETURNING guid
+        "#,
+        guid,
+        document.deleted_at
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state
+        .sync_manager
+        .notify_trash_change(
+            &restored,
+            TrashChange {
+                vault_id,
+                deleted: false,
+            },
+        )
+        .await;
+
+    tracing::info!(
+        "User {} restored document {} and {} nested documents of vault {}",
+        claims.sub,
+        guid,
+        restored.len().saturating_sub(1),
+        vault_id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletedDocumentResponse {
+    pub guid: String,
+    pub title: String,
+    pub doc_type: String,
+    pub parent_guid: Option<String>,
+    pub deleted_at: chrono::DateTime<chrono::Utc>,
+    pub deleted_by: Option<Uuid>,
+    pub days_until_permanent_deletion: i64,
+}
+
+/// Documents of the vault that are in the trash, most recently deleted first
+async fn list_deleted_documents(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(vault_id): Path<Uuid>,
+) -> Result<Json<Vec<DeletedDocumentResponse>>, StatusCode> {
+    let role = get_user_vault_role(&state.pool, vault_id, claims.sub)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if role == VaultRole::None {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let documents = sqlx::query!(
+        r#"
+        SELECT s.guid, s.doc_type, s.parent_guid, s.deleted_at AS "deleted_at!", s.deleted_by,
+               COALESCE(m.title, 'Untitled') AS "title!"
+        FROM subdocs s
+        LEFT JOIN subdoc_metadata m ON s.guid = m.subdoc_guid
+        WHERE s.vault_id = $1 AND s.deleted_at IS NOT NULL
+        ORDER BY s.deleted_at DESC
+        "#,
+        vault_id
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let response = documents
+        .into_iter()
+        .map(|doc| {
+            let days_until_permanent =
+                TRASH_RETENTION_DAYS - (chrono::Utc::now() - doc.deleted_at).num_days();
+            DeletedDocumentResponse {
+                guid: doc.guid,
+                title: doc.title,
+                doc_type: doc.doc_type,
+                parent_guid: doc.parent_guid,
+                deleted_at: doc.deleted_at,
+                deleted_by: doc.deleted_by,
+                days_until_permanent_deletion: days_until_permanent.max(0),
+            }
+        })
+        .collect();
+
+    Ok(Json(response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveDocumentRequest {
+    /// New parent document, or `null` to move the document to the top level
+    pub parent_guid: Option<String>,
+}
+
+/// Nest a document under another document of the vault, or move it to the top level
+async fn move_document(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((vault_id, guid)): Path<(Uuid, String)>,
+    Json(req): Json<MoveDocumentRequest>,
+) -> Result<Json<DocumentMetadata>, StatusCode> {
+    require_editor(&state.pool, vault_id, claims.sub).await?;
+
+    // The parent has to be a live document of this vault (400), and not the document
+    // itself or one of its descendants (409)
+    let metadata = state
+        .sync_manager
+        .move_document(&guid, vault_id, req.parent_guid.as_deref())
+        .await
+        .map_err(document_error)?;
+
+    tracing::info!(
+        "User {} moved document {} of vault {} under {:?}",
+        claims.sub,
+        guid,
+        vault_id,
+        req.parent_guid
+    );
+    Ok(Json(metadata))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentPresenceResponse {
+    pub guid: String,
+    pub users: Vec<PublicUserProfile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VaultPresenceResponse {
+    /// Everyone connected to the vault, whether or not they have a document open
+    pub users: Vec<PublicUserProfile>,
+    pub documents: Vec<DocumentPresenceResponse>,
+}
+
+/// Who is connected to the vault right now, and which documents they have open
+async fn get_vault_presence(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(vault_id): Path<Uuid>,
+) -> Result<Json<VaultPresenceResponse>, StatusCode> {
+    let role = get_user_vault_role(&state.pool, vault_id, claims.sub)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if role == VaultRole::None {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let presence = state.sync_manager.vault_presence(vault_id);
+    let user_ids: Vec<Uuid> = presence.user_ids.iter().copied().collect();
+    let profiles: HashMap<Uuid, PublicUserProfile> = sqlx::query_as::<_, PublicUserProfile>(
+        "SELECT id, username, display_name, avatar_url FROM users WHERE id = ANY($1)",
+    )
+    .bind(&user_ids)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .into_iter()
+    .map(|profile| (profile.id, profile))
+    .collect();
+
+    let profiles_of = |user_ids: &BTreeSet<Uuid>| -> Vec<PublicUserProfile> {
+        user_ids
+            .iter()
+            .filter_map(|id| profiles.get(id).cloned())
+            .collect()
+    };
+
+    Ok(Json(VaultPresenceResponse {
+        users: profiles_of(&presence.user_ids),
+        documents: presence
+            .documents
+            .iter()
+            .map(|(guid, user_ids)| DocumentPresenceResponse {
+                guid: guid.clone(),
+                users: profiles_of(user_ids),
+            })
+            .collect(),
+    }))
+}
+
+/// Document types the sync server may create on its own
+const AUTO_CREATE_DOC_TYPES: [&str; 2] = ["document", "canvas"];
+
+async fn get_vault_settings(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(vault_id): Path<Uuid>,
+) -> Result<Json<VaultSettings>, StatusCode> {
+    let role = get_user_vault_role(&state.pool, vault_id, claims.sub)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if role == VaultRole::None {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let vault = sqlx::query!(
+        "SELECT doc_creation_policy, auto_create_doc_type, auto_create_parent_guid, restore_on_edit
+         FROM vaults WHERE id = $1",
+        vault_id
+    )
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(VaultSettings {
+        doc_creation: DocCreationPolicy::from_columns(
+            &vault.doc_creation_policy,
+            vault.auto_create_doc_type,
+            vault.auto_create_parent_guid,
+        ),
+        restore_on_edit: vault.restore_on_edit,
+    }))
+}
+
+async fn update_vault_settings(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(vault_id): Path<Uuid>,
+    Json(settings): Json<VaultSettings>,
+) -> Result<Json<VaultSettings>, StatusCode> {
+    let role = get_user_vault_role(&state.pool, vault_id, claims.sub)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match role {
+        VaultRole::None => return Err(StatusCode::NOT_FOUND),
+        VaultRole::Owner => {}
+        _ => return Err(StatusCode::FORBIDDEN),
+    }
+
+    let (policy, doc_type, parent_guid) = match &settings.doc_creation {
+        DocCreationPolicy::Reject => ("reject", "document", None),
+        DocCreationPolicy::AutoCreate {
+            doc_type,
+            parent_guid,
+        } => {
+            if !AUTO_CREATE_DOC_TYPES.contains(&doc_type.as_str()) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            // The parent has to be a live document of this vault
+            if let Some(parent_guid) = parent_guid {
+                let parent_exists = sqlx::query_scalar!(
+                    r#"SELECT EXISTS (
+                        SELECT 1 FROM subdocs
+                        WHERE guid = $1 AND vault_id = $2 AND deleted_at IS NULL
+                    ) AS "exists!""#,
+                    parent_guid,
+                    vault_id
+                )
+                .fetch_one(&state.pool)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                if !parent_exists {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+            }
+            ("auto_create", doc_type.as_str(), parent_guid.as_deref())
+        }
+    };
+
+    sqlx::query!(
+        "UPDATE vaults
+         SET doc_creation_policy = $2, auto_create_doc_type = $3, auto_create_parent_guid = $4,
+             restore_on_edit = $5
+         WHERE id = $1",
+        vault_id,
+        policy,
+        doc_type,
+        parent_guid,
+        settings.restore_on_edit,
+    )
+    .execute(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!(
+        "User {} updated settings of vault {}: {:?}",
+        claims.sub,
+        vault_id,
+        settings
+    );
+    Ok(Json(settings))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AddVaultMemberRequest {
     pub user_id: Option<Uuid>,
@@ -794,6 +1324,12 @@ async fn add_vault_member(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    // Membership can lift a viewer that had access through an org
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::vault(vault_id, Some(target_user_id)))
+        .await;
+
     Ok(Json(VaultMemberWithProfileResponse::from(member)))
 }
 
@@ -832,16 +1368,20 @@ async fn remove_vault_member(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    let result = sqlx::query("DELETE FROM vault_members WHERE id = $1 AND vault_id = $2")
-        .bind(member_id)
-        .bind(vault_id)
-        .execute(&state.pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let member = sqlx::query_as::<_, VaultMember>(
+        "DELETE FROM vault_members WHERE id = $1 AND vault_id = $2 RETURNING id, vault_id, user_id, role, invited_by, joined_at, created_at",
+    )
+    .bind(member_id)
+    .bind(vault_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
 
-    if result.rows_affected() == 0 {
-        return Err(StatusCode::NOT_FOUND);
-    }
+    state
+        .sync_manager
+        .notify_access_change(AccessChange::vault(member.vault_id, Some(member.user_id)))
+        .await;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -881,24 +1421,49 @@ async fn restore_vault(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deleted_at = vault.deleted_at;
     let vault = sqlx::query_as::<_, Vault>(
-        "UPDATE vaults SET deleted_at = NULL, deleted_by = NULL WHERE id = $1 AND deleted_at IS NOT NULL RETURNING id, user_id, org_id, vault_type, name, created_at, deleted_at, deleted_by",
+        "UPDATE vaults SET deleted_at = NULL, deleted_by = NULL WHERE id = $1 AND deleted_at = $2 RETURNING id, user_id, org_id, vault_type, name, created_at, deleted_at, deleted_by",
     )
     .bind(vault_id)
-    .fetch_optional(&state.pool)
+    .bind(deleted_at)
+    .fetch_optional(&mut *tx)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     .ok_or(StatusCode::NOT_FOUND)?;
 
-    // Restore all subdocs in the vault
-    sqlx::query(
-        "UPDATE subdocs SET deleted_at = NULL, deleted_by = NULL WHERE vault_id = $1 AND deleted_at IS NOT NULL",
+    // Only the documents the vault took with it: those trashed on their own before
+    // the vault was deleted stay in the trash, on their own retention clock
+    let restored = sqlx::query_scalar::<_, String>(
+        "UPDATE subdocs SET deleted_at = NULL, deleted_by = NULL WHERE vault_id = $1 AND deleted_at >= $2 RETURNING guid",
     )
     .bind(vault_id)
-    .execute(&state.pool)
+    .bind(deleted_at)
+    .fetch_all(&mut *tx)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state
+        .sync_manager
+        .notify_trash_change(
+            &restored,
+            TrashChange {
+                vault_id,
+                deleted: false,
+            },
+        )
+        .await;
+
     Ok(Json(VaultResponse::from(vault)))
 }
 
@@ -935,7 +1500,8 @@ async fn list_deleted_vaults(
         .into_iter()
         .filter_map(|v| {
             let deleted_at = v.deleted_at?;
-            let days_until_permanent = 30 - (chrono::Utc::now() - deleted_at).num_days();
+            let days_until_permanent =
+                TRASH_RETENTION_DAYS - (chrono::Utc::now() - deleted_at).num_days();
             Some(DeletedVaultResponse {
                 id: v.id,
                 name: v.name,
@@ -947,3 +1513,388 @@ async fn list_deleted_vaults(
 
     Ok(Json(response))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SyncConfig;
+    use crate::test_support::{app_state, create_user, create_vault};
+
+    struct Fixture {
+        state: AppState,
+        owner: Uuid,
+        vault_id: Uuid,
+    }
+
+    async fn setup(pool: PgPool) -> Fixture {
+        let owner = create_user(&pool).await;
+        let vault_id = create_vault(&pool, owner).await;
+        Fixture {
+            state: app_state(pool, SyncConfig::default()),
+            owner,
+            vault_id,
+        }
+    }
+
+    fn claims(user_id: Uuid) -> Claims {
+        Claims {
+            sub: user_id,
+            exp: usize::MAX,
+        }
+    }
+
+    impl Fixture {
+        async fn create(&self, title: &str, parent_guid: Option<&str>) -> String {
+            let Json(created) = create_document(
+                State(self.state.clone()),
+                claims(self.owner),
+                Path(self.vault_id),
+                Json(CreateDocumentRequest {
+                    title: title.to_string(),
+                    parent_guid: parent_guid.map(str::to_string),
+                }),
+            )
+            .await
+            .unwrap();
+            created.guid
+        }
+
+        async fn update(
+            &self,
+            user_id: Uuid,
+            guid: &str,
+            patch: DocumentMetadataPatch,
+        ) -> Result<DocumentMetadata, StatusCode> {
+            update_document(
+                State(self.state.clone()),
+                claims(user_id),
+                Path((self.vault_id, guid.to_string())),
+                Json(patch),
+            )
+            .await
+            .map(|Json(metadata)| metadata)
+        }
+
+        async fn delete(&self, user_id: Uuid, guid: &str) -> StatusCode {
+            delete_document(
+                State(self.state.clone()),
+                claims(user_id),
+                Path((self.vault_id, guid.to_string())),
+            )
+            .await
+            .unwrap_or_else(|status| status)
+        }
+
+        async fn restore(&self, user_id: Uuid, guid: &str) -> StatusCode {
+            restore_document(
+                State(self.state.clone()),
+                claims(user_id),
+                Path((self.vault_id, guid.to_string())),
+            )
+            .await
+            .unwrap_or_else(|status| status)
+        }
+
+        async fn move_to(
+            &self,
+            user_id: Uuid,
+            guid: &str,
+            parent_guid: Option<&str>,
+        ) -> Result<DocumentMetadata, StatusCode> {
+            move_document(
+                State(self.state.clone()),
+                claims(user_id),
+                Path((self.vault_id, guid.to_string())),
+                Json(MoveDocumentRequest {
+                    parent_guid: parent_guid.map(str::to_string),
+                }),
+            )
+            .await
+            .map(|Json(metadata)| metadata)
+        }
+
+        async fn deleted_at(&self, guid: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+            sqlx::query_scalar("SELECT deleted_at FROM subdocs WHERE guid = $1")
+                .bind(guid)
+                .fetch_one(&self.state.pool)
+                .await
+                .unwrap()
+        }
+
+        async fn trashed(&self, guid: &str) -> bool {
+            sqlx::query_scalar::<_, bool>(
+                "SELECT deleted_at IS NOT NULL FROM subdocs WHERE guid = $1",
+            )
+            .bind(guid)
+            .fetch_one(&self.state.pool)
+            .await
+            .unwrap()
+        }
+    }
+
+    #[sqlx::test]
+    async fn updates_change_saved_documents_without_loading_unknown_ones(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let guid = fixture.create("Draft", None).await;
+
+        let metadata = fixture
+            .update(
+                fixture.owner,
+                &guid,
+                DocumentMetadataPatch {
+                    title: Some("Final".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(metadata.title, "Final");
+
+        // The vault creates unknown guids on the first edit, but a metadata patch is no edit
+        let unknown = fixture
+            .update(
+                fixture.owner,
+                "never-created",
+                DocumentMetadataPatch::default(),
+            )
+            .await;
+        assert_eq!(unknown.unwrap_err(), StatusCode::NOT_FOUND);
+        assert!(
+            fixture
+                .state
+                .sync_manager
+                .active_documents()
+                .await
+                .is_empty()
+        );
+
+        assert_eq!(
+            fixture.delete(fixture.owner, &guid).await,
+            StatusCode::NO_CONTENT
+        );
+        let trashed = fixture
+            .update(fixture.owner, &guid, DocumentMetadataPatch::default())
+            .await;
+        assert_eq!(trashed.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn moves_keep_the_document_tree_acyclic(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let root = fixture.create("Root", None).await;
+        let child = fixture.create("Child", Some(&root)).await;
+        let other = fixture.create("Other", None).await;
+
+        let moved = fixture.move_to(fixture.owner, &other, Some(&child)).await;
+        assert_eq!(moved.unwrap().parent_guid.as_deref(), Some(child.as_str()));
+
+        // Under itself, or under a descendant two levels down
+        for parent in [&root, &other] {
+            let cycle = fixture.move_to(fixture.owner, &root, Some(parent)).await;
+            assert_eq!(cycle.unwrap_err(), StatusCode::CONFLICT);
+        }
+
+        let missing = fixture
+            .move_to(fixture.owner, &child, Some("no-such-parent"))
+            .await;
+        assert_eq!(missing.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            fixture.delete(fixture.owner, &other).await,
+            StatusCode::NO_CONTENT
+        );
+        let trashed_parent = fixture.move_to(fixture.owner, &child, Some(&other)).await;
+        assert_eq!(trashed_parent.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let top_level = fixture.move_to(fixture.owner, &child, None).await;
+        assert_eq!(top_level.unwrap().parent_guid, None);
+    }
+
+    #[sqlx::test]
+    async fn deleting_and_restoring_carries_nested_documents(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let root = fixture.create("Root", None).await;
+        let child = fixture.create("Child", Some(&root)).await;
+        let grandchild = fixture.create("Grandchild", Some(&child)).await;
+        let earlier = fixture.create("Trashed earlier", Some(&root)).await;
+
+        assert_eq!(
+            fixture.delete(fixture.owner, &earlier).await,
+            StatusCode::NO_CONTENT
+        );
+        // Tell the two deletions apart
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(
+            fixture.delete(fixture.owner, &root).await,
+            StatusCode::NO_CONTENT
+        );
+        for guid in [&root, &child, &grandchild, &earlier] {
+            assert!(fixture.trashed(guid).await, "{} is not in the trash", guid);
+        }
+        assert_eq!(
+            fixture.delete(fixture.owner, &root).await,
+            StatusCode::NOT_FOUND
+        );
+
+        // The parent has to come back first
+        assert_eq!(
+            fixture.restore(fixture.owner, &child).await,
+            StatusCode::CONFLICT
+        );
+        assert!(fixture.trashed(&child).await);
+
+        assert_eq!(
+            fixture.restore(fixture.owner, &root).await,
+            StatusCode::NO_CONTENT
+        );
+        for guid in [&root, &child, &grandchild] {
+            assert!(
+                !fixture.trashed(guid).await,
+                "{} is still in the trash",
+                guid
+            );
+        }
+        assert!(fixture.trashed(&earlier).await);
+        assert_eq!(
+            fixture.restore(fixture.owner, &root).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[sqlx::test]
+    async fn restoring_a_vault_leaves_documents_trashed_on_their_own(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let kept = fixture.create("Kept", None).await;
+        let discarded = fixture.create("Discarded", None).await;
+        assert_eq!(
+            fixture.delete(fixture.owner, &discarded).await,
+            StatusCode::NO_CONTENT
+        );
+        let before = fixture.deleted_at(&discarded).await;
+
+        let status = delete_vault(
+            State(fixture.state.clone()),
+            claims(fixture.owner),
+            Path(fixture.vault_id),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(fixture.trashed(&kept).await);
+
+        let Json(restored) = restore_vault(
+            State(fixture.state.clone()),
+            claims(fixture.owner),
+            Path(fixture.vault_id),
+        )
+        .await
+        .unwrap();
+        assert_eq!(restored.id, fixture.vault_id);
+        assert!(!fixture.trashed(&kept).await);
+        assert!(fixture.trashed(&discarded).await);
+        assert_eq!(fixture.deleted_at(&discarded).await, before);
+    }
+
+    #[sqlx::test]
+    async fn viewers_cannot_change_documents(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let guid = fixture.create("Shared", None).await;
+        let viewer = create_user(&fixture.state.pool).await;
+        sqlx::query(
+            "INSERT INTO vault_members (vault_id, user_id, role) VALUES ($1, $2, 'viewer')",
+        )
+        .bind(fixture.vault_id)
+        .bind(viewer)
+        .execute(&fixture.state.pool)
+        .await
+        .unwrap();
+        let stranger = create_user(&fixture.state.pool).await;
+
+        for (user_id, status) in [
+            (viewer, StatusCode::FORBIDDEN),
+            (stranger, StatusCode::NOT_FOUND),
+        ] {
+            let patch = DocumentMetadataPatch {
+                title: Some("Mine now".to_string()),
+                ..Default::default()
+            };
+            assert_eq!(
+                fixture.update(user_id, &guid, patch).await.unwrap_err(),
+                status
+            );
+            assert_eq!(
+                fixture.move_to(user_id, &guid, None).await.unwrap_err(),
+                status
+            );
+            assert_eq!(fixture.delete(user_id, &guid).await, status);
+        }
+        assert!(!fixture.trashed(&guid).await);
+
+        assert_eq!(
+            fixture.delete(fixture.owner, &guid).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(fixture.restore(viewer, &guid).await, StatusCode::FORBIDDEN);
+        assert!(fixture.trashed(&guid).await);
+    }
+
+    #[sqlx::test]
+    async fn the_trash_counts_down_to_permanent_deletion(pool: PgPool) {
+        let fixture = setup(pool).await;
+        let recent = fixture.create("Recent", None).await;
+        let expiring = fixture.create("Expiring", None).await;
+        let overdue = fixture.create("Overdue", None).await;
+        fixture.create("Live", None).await;
+        for (guid, days_ago) in [(&recent, 10), (&expiring, 29), (&overdue, 45)] {
+            assert_eq!(
+                fixture.delete(fixture.owner, guid).await,
+                StatusCode::NO_CONTENT
+            );
+            sqlx::query(
+                "UPDATE subdocs SET deleted_at = NOW() - make_interval(days => $2) WHERE guid = $1",
+            )
+            .bind(guid)
+            .bind(days_ago)
+            .execute(&fixture.state.pool)
+            .await
+            .unwrap();
+        }
+
+        let Json(trash) = list_deleted_documents(
+            State(fixture.state.clone()),
+            claims(fixture.owner),
+            Path(fixture.vault_id),
+        )
+        .await
+        .unwrap();
+        let listed: Vec<_> = trash
+            .iter()
+            .map(|doc| {
+                (
+                    doc.guid.as_str(),
+                    doc.title.as_str(),
+                    doc.deleted_by,
+                    doc.days_until_permanent_deletion,
+                )
+            })
+            .collect();
+        let owner = Some(fixture.owner);
+        assert_eq!(
+            listed,
+            vec![
+                (recent.as_str(), "Recent", owner, TRASH_RETENTION_DAYS - 10),
+                (expiring.as_str(), "Expiring", owner, 1),
+                (overdue.as_str(), "Overdue", owner, 0),
+            ]
+        );
+
+        let stranger = create_user(&fixture.state.pool).await;
+        let hidden = list_deleted_documents(
+            State(fixture.state.clone()),
+            claims(stranger),
+            Path(fixture.vault_id),
+        )
+        .await;
+        assert_eq!(hidden.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+}
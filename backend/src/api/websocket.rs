@@ -1,72 +1,73 @@
+use crate::api::audit::log_document_created;
 use crate::api::auth::AppState;
 use crate::api::vaults::{VaultRole, get_user_vault_role};
-use crate::models::DocumentMetadata;
+use crate::auth::{jwt::Claims, validate_token};
+use crate::models::DocumentMetadataPatch;
+use crate::sync::protocol::{
+    ControlMessage, ErrorCode, ErrorFrame, Extension, MAX_GUID_BYTES, ProtocolError, decode_frame,
+    read_guid, read_var_string, write_var_string,
+};
+use crate::sync::{
+    BroadcastMessage, DocumentDeleted, DocumentNotFound, LimitExceeded, RateLimiter, VaultMessage,
+    ViolationWindow,
+};
 use axum::{
     body::Bytes,
     extract::{
         Path, Query, State,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
     },
     http::StatusCode,
     response::Response,
 };
-use chrono::Utc;
+use futures_util::stream::{BoxStream, SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use std::collections::HashMap;
-use tokio::sync::broadcast;
+use tokio_stream::StreamMap;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use uuid::Uuid;
+use yrs::sync::awareness::AwarenessUpdate;
+use yrs::sync::{Message as YMessage, SyncMessage};
 use yrs::updates::decoder::Decode;
 use yrs::updates::encoder::Encode;
-use yrs::{Doc, GetString, ReadTxn, StateVector, Transact, Update};
+use yrs::{StateVector, Update};
 
-/// Metadata extracted from a Yjs update for audit logging
-#[derive(Debug)]
-struct EditMetadata {
-    edit_type: String,
-    block_type: Option<String>,
-    block_position: Option<i32>,
-    content_before: Option<String>,
-    content_after: Option<String>,
-}
+/// Broadcast streams of the documents a socket is subscribed to, keyed by document GUID
+type Subscriptions = StreamMap<String, BroadcastStream<BroadcastMessage>>;
 
-fn read_var_from_slice(data: &[u8]) -> anyhow::Result<(u32, &[u8])> {
-    let mut value: u32 = 0;
-    let mut shift = 0;
-    let mut pos = 0;
+/// Server state vector each subscribed document was last fully synced to, keyed by GUID
+type SyncedStates = HashMap<String, StateVector>;
 
-    loop {
-        if pos >= data.len() {
-            return Err(anyhow::anyhow!("varint decoding ran out of bytes"));
-        }
+/// Close sockets that have not sent anything (including pongs) for this long
+const CLIENT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(30);
 
-        let byte = data[pos] as u32;
-        pos += 1;
+/// Sockets connecting without `?token` must send an `auth` control message within this long
+const AUTH_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
 
-        value |= (byte & 0x7F) << shift;
+/// How long before the access token expires the client is asked to re-authenticate
+const TOKEN_EXPIRY_WARNING: tokio::time::Duration = tokio::time::Duration::from_secs(5 * 60);
 
-        if byte & 0x80 == 0 {
-            break;
-        }
+/// Close code sent when authentication failed or the access token expired
+const CLOSE_UNAUTHORIZED: u16 = 4001;
 
-        shift += 7;
-    }
+/// Close code sent when the user has no (or no longer any) access to the vault
+const CLOSE_ACCESS_REVOKED: u16 = 4003;
 
-    Ok((value, &data[pos..]))
-}
+/// Close code sent when a client exceeded the sync limits too often (policy violation)
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
 
-fn encode_var_uint(buf: &mut Vec<u8>, mut value: u32) -> anyhow::Result<()> {
-    loop {
-        let mut byte = (value & 0x7F) as u8;
-        value >>= 7;
-        if value > 0 {
-            byte |= 0x80;
-        }
-        buf.push(byte);
-        if value == 0 {
-            break;
-        }
-    }
-    Ok(())
+/// Close code sent when the server shuts down (service restart); clients should reconnect
+const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// How frames on a connection name the document they belong to
+#[derive(Debug)]
+enum Addressing {
+    /// `/ws/{doc}`: every frame belongs to the document in the path
+    Single(Option<String>),
+    /// `/ws`: every frame is prefixed with varString(guid), in both directions.
+    /// A guid followed by an empty body unsubscribes from that document.
+    Multiplexed,
 }
 
 // /ws/:doc WebSocket handler
@@ -81,18 +82,58 @@ pub async fn ws_handler(
     tracing::info!("ws upgrade for doc path param: {:?}", doc);
     tracing::debug!("ws upgrade query params: {:?}", query);
 
-    // Extract and validate JWT token from query parameters
-    let token = query.get("token").ok_or_else(|| {
-        tracing::warn!("WebSocket connection attempt without token");
-        StatusCode::UNAUTHORIZED
-    })?;
+    // Prefer path param when present, otherwise fallback to ?doc=<id> query parameter
+    let path_doc = if !doc.is_empty() {
+        Some(doc)
+    } else {
+        query.get("doc").cloned()
+    };
 
-    let claims = crate::auth::validate_token(token, &state.config.jwt_secret).map_err(|e| {
-        tracing::warn!("Invalid token: {:?}", e);
-        StatusCode::UNAUTHORIZED
-    })?;
+    upgrade(ws, state, &query, Addressing::Single(path_doc)).await
+}
 
-    let user_id = claims.sub;
+// /ws WebSocket handler, syncing any number of documents of one vault
+#[axum::debug_handler]
+pub async fn ws_multiplex_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    tracing::info!("ws upgrade for multiplexed connection");
+    tracing::debug!("ws upgrade query params: {:?}", query);
+
+    upgrade(ws, state, &query, Addressing::Multiplexed).await
+}
+
+/// Authenticate the upgrade request and hand the socket over to a new session
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    state: AppState,
+    query: &HashMap<String, String>,
+    addressing: Addressing,
+) -> Result<Response, StatusCode> {
+    // Sessions are being closed so the server can exit; clients retry elsewhere
+    if state.sync_manager.is_shutting_down() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    if let Addressing::Single(Some(guid)) = &addressing
+        && (guid.is_empty() || guid.len() > MAX_GUID_BYTES)
+    {
+        tracing::warn!("Refusing upgrade for guid of {} bytes", guid.len());
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // Validate the JWT token from the query parameters. Without one, the client has to
+    // authenticate in-band, which keeps the token out of URLs (and proxy logs).
+    let claims = query
+        .get("token")
+        .map(|token| validate_token(token, &state.config.jwt_secret))
+        .transpose()
+        .map_err(|e| {
+            tracing::warn!("Invalid token: {:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
 
     // Extract vault_id from query parameters
     let vault_id_str = query.get("vaultId").ok_or_else(|| {
@@ -105,13 +146,48 @@ pub async fn ws_handler(
         StatusCode::BAD_REQUEST
     })?;
 
-    // Get user's role for this vault
+    let auth = match claims {
+        Some(claims) => {
+            let role = vault_role(&state, vault_id, claims.sub)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Some((claims, role))
+        }
+        None => {
+            tracing::info!(
+                "WebSocket without token, expecting in-band auth: vault_id={}",
+                vault_id
+            );
+            None
+        }
+    };
+
+    // Generate session ID for tracking this WebSocket connection
+    let session_id = Uuid::new_v4();
+    tracing::info!(
+        "WebSocket session created: session_id={}, vault_id={}",
+        session_id,
+        vault_id
+    );
+
+    // Messages over the frame limit are refused by the session, but only after they have
+    // been buffered; far bigger ones are not read in the first place. The multiplexing
+    // prefix is a varString of at most MAX_GUID_BYTES (a varUint length takes up to 5 bytes)
+    let max_message_bytes = state.config.sync.max_frame_bytes + 5 + MAX_GUID_BYTES;
+    let ws = ws
+        .max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes);
+
+    Ok(ws.on_upgrade(move |socket| {
+        handle_socket(socket, state, addressing, vault_id, auth, session_id)
+    }))
+}
+
+/// Look up the user's role for the vault, logging the outcome
+async fn vault_role(state: &AppState, vault_id: Uuid, user_id: Uuid) -> anyhow::Result<VaultRole> {
     let role = get_user_vault_role(&state.pool, vault_id, user_id)
         .await
-        .map_err(|e| {
-            tracing::error!("Database error checking vault role: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .inspect_err(|e| tracing::error!("Database error checking vault role: {:?}", e))?;
 
     let has_vault_access = role != VaultRole::None;
 
@@ -130,51 +206,135 @@ pub async fn ws_handler(
         has_vault_access
     );
 
-    // Prefer path param when present, otherwise fallback to ?doc=<id> query parameter
-    let path_doc = if !doc.is_empty() {
-        Some(doc)
-    } else {
-        query.get("doc").cloned()
+    Ok(role)
+}
+
+/// Wait for the client's `auth` control message and check the token it carries
+async fn authenticate_in_band(
+    receiver: &mut SplitStream<WebSocket>,
+    state: &AppState,
+    addressing: &Addressing,
+    vault_id: Uuid,
+) -> Result<(Claims, VaultRole), &'static str> {
+    let token = tokio::time::timeout(AUTH_TIMEOUT, async {
+        loop {
+            match receiver.next().await {
+                Some(Ok(Message::Binary(data))) => return read_auth_token(addressing, &data),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    return Err("connection closed");
+                }
+                Some(Ok(_)) => continue,
+            }
+        }
+    })
+    .await
+    .map_err(|_| "authentication timed out")??;
+
+    let claims = validate_token(&token, &state.config.jwt_secret).map_err(|e| {
+        tracing::warn!("Invalid in-band token: {:?}", e);
+        "invalid token"
+    })?;
+    let role = vault_role(state, vault_id, claims.sub)
+        .await
+        .map_err(|_| "failed to check vault access")?;
+
+    Ok((claims, role))
+}
+
+/// Extract the token from a frame that has to be an `auth` control message
+fn read_auth_token(addressing: &Addressing, data: &[u8]) -> Result<String, &'static str> {
+    let body = match addressing {
+        Addressing::Single(_) => data,
+        Addressing::Multiplexed => {
+            read_var_string(data)
+                .map_err(|_| "expected an auth message")?
+                .1
+        }
     };
 
-    // Generate session ID for tracking this WebSocket connection
-    let session_id = Uuid::new_v4();
-    tracing::info!(
-        "WebSocket session created: session_id={}, user_id={}, vault_id={}",
-        session_id,
-        user_id,
-        vault_id
-    );
+    match decode_frame(body).as_deref() {
+        Ok([YMessage::Custom(tag, data)]) => match Extension::from_custom(*tag, data) {
+            Ok(Extension::Control(ControlMessage::Auth { token })) => Ok(token),
+            _ => Err("expected an auth message"),
+        },
+        _ => Err("expected an auth message"),
+    }
+}
 
-    Ok(ws.on_upgrade(move |socket| {
-        handle_socket(socket, state, path_doc, vault_id, user_id, role, session_id)
+/// Wrap a message about `guid` in a binary frame, prefixing it with the guid on
+/// multiplexed connections
+fn frame(addressing: &Addressing, guid: &str, msg: Vec<u8>) -> Message {
+    let frame = match addressing {
+        Addressing::Single(_) => msg,
+        Addressing::Multiplexed => {
+            let mut frame = Vec::with_capacity(guid.len() + msg.len() + 5);
+            write_var_string(&mut frame, guid);
+            frame.extend_from_slice(&msg);
+            frame
+        }
+    };
+    Message::Binary(Bytes::from(frame))
+}
+
+/// y-protocols auth message telling the client it may not access the vault,
+/// sent before the socket is closed with [`CLOSE_ACCESS_REVOKED`]
+fn permission_denied(addressing: &Addressing, reason: &str) -> Message {
+    frame(
+        addressing,
+        "",
+        YMessage::Auth(Some(reason.to_string())).encode_v1(),
+    )
+}
+
+fn close_message(code: u16, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
     }))
 }
 
 async fn handle_socket(
     socket: WebSocket,
     state: AppState,
-    doc: Option<String>,
+    addressing: Addressing,
     vault_id: Uuid,
-    user_id: Uuid,
-    role: VaultRole,
+    auth: Option<(Claims, VaultRole)>,
     session_id: Uuid,
 ) {
+    let (mut sender, mut receiver) = socket.split();
+    tracing::info!("WebSocket split into sender/receiver");
+
+    let authenticated_in_band = auth.is_none();
+    let (claims, role) = match auth {
+        Some(auth) => auth,
+        None => match authenticate_in_band(&mut receiver, &state, &addressing, vault_id).await {
+            Ok(auth) => auth,
+            Err(reason) => {
+                tracing::warn!("In-band authentication failed: {}", reason);
+                let _ = sender.send(close_message(CLOSE_UNAUTHORIZED, reason)).await;
+                return;
+            }
+        },
+    };
+    let user_id = claims.sub;
+
     tracing::info!(
-        "WebSocket connection established, doc: {:?}, vault_id={}, user_id={}, role={:?}, session_id={}",
-        doc,
+        "WebSocket connection established, addressing: {:?}, vault_id={}, user_id={}, role={:?}, session_id={}",
+        addressing,
         vault_id,
         user_id,
         role,
         session_id
     );
 
-    let (mut sender, mut receiver) = socket.split();
-    tracing::info!("WebSocket split into sender/receiver");
-
     // If user doesn't have vault access, close connection immediately
     if role == VaultRole::None {
-        let _ = sender.send(Message::Close(None)).await;
+        let _ = sender
+            .send(permission_denied(&addressing, "vault access denied"))
+            .await;
+        let _ = sender
+            .send(close_message(CLOSE_ACCESS_REVOKED, "vault access denied"))
+            .await;
         tracing::warn!(
             "Closed WebSocket connection for user {} - vault access denied",
             user_id
@@ -182,26 +342,114 @@ async fn handle_socket(
         return;
     }
 
-    // Track which documents this client is subscribed to
-    let mut subscriptions: HashMap<String, broadcast::Receiver<Vec<u8>>> = HashMap::new();
+    let _tracked = state
+        .sync_manager
+        .track_session(session_id, user_id, vault_id);
+    let mut shutdown = state.sync_manager.subscribe_shutdown();
+
+    let mut access_changes = BroadcastStream::new(state.sync_manager.subscribe_access_changes());
+
+    // Multiplexed connections also hear about documents of the vault they have not opened
+    let mut vault_messages: BoxStream<'static, Result<VaultMessage, BroadcastStreamRecvError>> =
+        match addressing {
+            Addressing::Multiplexed => {
+                BroadcastStream::new(state.sync_manager.subscribe_vault(vault_id).await).boxed()
+            }
+            Addressing::Single(_) => futures_util::stream::pending().boxed(),
+        };
+
+    let update_rate = RateLimiter::per_second(state.config.sync.max_updates_per_sec);
+    let violations = ViolationWindow::new(tokio::time::Duration::from_secs(
+        state.config.sync.violation_window_secs,
+    ));
+    let mut session = Session {
+        sender,
+        state,
+        addressing,
+        subscriptions: Subscriptions::new(),
+        synced: SyncedStates::new(),
+        vault_id,
+        user_id,
+        role,
+        session_id,
+        expires_at: claims.exp as i64,
+        expiry_warned: false,
+        update_rate,
+        violations,
+    };
+
+    if authenticated_in_band {
+        let expires_at = session.expires_at;
+        if let Err(e) = session
+            .send_control(ControlMessage::Authenticated { expires_at })
+            .await
+        {
+            tracing::info!("Failed to acknowledge in-band auth: {}", e);
+            return;
+        }
+    }
+
+    let sync_manager = session.state.sync_manager.clone();
+    let max_violations = session.state.config.sync.max_violations;
+    sync_manager.metrics().session_opened();
 
     // Keep-alive: send ping every 5 seconds to prevent client timeout
     let mut keep_alive_interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+    let mut last_seen = tokio::time::Instant::now();
 
     loop {
         tokio::select! {
             // Keep-alive ping
             _ = keep_alive_interval.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    tracing::info!("Client timed out after {:?} of silence", CLIENT_TIMEOUT);
+                    break;
+                }
+
                 tracing::debug!("Sending keep-alive ping");
-                if let Err(e) = sender.send(Message::Ping(Bytes::new())).await {
+                if let Err(e) = session.sender.send(Message::Ping(Bytes::new())).await {
                     tracing::info!("Failed to send keep-alive ping: {}", e);
                     break;
                 }
             }
 
+            // The server is going away; tell the client to reconnect, to another instance
+            // or to this one once it is back
+            _ = async { shutdown.wait_for(|stopping| *stopping).await.map(drop) } => {
+                tracing::info!("Closing session {} for shutdown", session.session_id);
+                let _ = session
+                    .sender
+                    .send(close_message(CLOSE_SERVICE_RESTART, "server restarting, reconnect"))
+                    .await;
+                break;
+            }
+
+            // Warn the client before its token expires, and close the session once it has
+            _ = tokio::time::sleep_until(session.token_deadline()) => {
+                if session.expiry_warned {
+                    tracing::info!("Access token expired (session_id={})", session.session_id);
+                    let _ = session
+                        .sender
+                        .send(close_message(CLOSE_UNAUTHORIZED, "token expired"))
+                        .await;
+                    break;
+                }
+
+                session.expiry_warned = true;
+                let expires_at = session.expires_at;
+                if let Err(e) = session
+                    .send_control(ControlMessage::TokenExpiring { expires_at })
+                    .await
+                {
+                    tracing::info!("Failed to send token expiry warning: {}", e);
+                    break;
+                }
+            }
+
             // Handle incoming messages from client
             msg = receiver.next() => {
                 tracing::info!("Received something from socket: {:?}", msg.is_some());
+                last_seen = tokio::time::Instant::now();
                 match msg {
                     Some(Ok(Message::Binary(data))) => {
                         // Diagnostic logging for incoming binary frames
@@ -217,13 +465,29 @@ async fn handle_socket(
                             tracing::warn!("Received Binary message with zero length");
                         }
 
-                        match handle_binary_message(&mut sender, &state, &data, &mut subscriptions, &doc, vault_id, user_id, role, session_id).await {
+                        sync_manager.metrics().frame_received(data.len());
+                        match session.handle_frame(&data).await {
                             Ok(_) => tracing::debug!("Message handled successfully"),
                             Err(e) => {
                                 tracing::error!("Error handling binary message: {:?}", e);
                                 // Don't close the connection on error, just log and continue
                             }
                         }
+
+                        let violations = session.violations.count();
+                        if violations >= max_violations {
+                            tracing::warn!(
+                                "Disconnecting session {} after {} limit violations",
+                                session.session_id,
+                                violations
+                            );
+                            sync_manager.metrics().session_disconnected();
+                            let _ = session
+                                .sender
+                                .send(close_message(CLOSE_POLICY_VIOLATION, "too many limit violations"))
+                                .await;
+                            break;
+                        }
                     }
                     Some(Ok(Message::Close(frame))) => {
                         tracing::info!("Client closed connection (close frame = {:?})", frame);
@@ -249,544 +513,785 @@ async fn handle_socket(
                 }
             }
 
-            // Handle broadcast updates from other clients
-            // Only select on broadcast if we have subscriptions
-            update = async {
-                if subscriptions.is_empty() {
-                    std::future::pending().await
-                } else {
-                    for (_guid, receiver) in subscriptions.iter_mut() {
-                        match receiver.try_recv() {
-                            Ok(update_data) => {
-                                tracing::info!("Got broadcast update: {} bytes", update_data.len());
-                                return Some(update_data);
-                            }
-                            Err(broadcast::error::TryRecvError::Empty) => continue,
-                            Err(broadcast::error::TryRecvError::Lagged(_)) => {
-                                tracing::warn!("Client lagged behind on updates");
-                            }
-                            Err(broadcast::error::TryRecvError::Closed) => {}
+            // Handle broadcast updates from other clients; wakes only when one arrives
+            Some((guid, update)) = session.subscriptions.next(), if !session.subscriptions.is_empty() => {
+                let update_data = match update {
+                    // Clients already have their own changes; echoing them back is wasted bandwidth
+                    Ok(message) if message.origin == Some(session.session_id) => continue,
+                    Ok(message) => message.payload,
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        // The client missed updates and would silently diverge; send it
+                        // everything since its last full sync instead
+                        tracing::warn!(
+                            "Client lagged behind on document {} ({} updates skipped), resyncing",
+                            guid,
+                            skipped
+                        );
+                        if let Err(e) = session.resync_lagged(&guid).await {
+                            tracing::error!("Failed to resync lagged client on {}: {:?}", guid, e);
+                            break;
                         }
+                        continue;
                     }
-                    // If no updates available, sleep briefly to avoid busy loop
-                    tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-                    None::<Vec<u8>>
-                }
-            }, if !subscriptions.is_empty() => {
-                if let Some(update_data) = update {
-                    // Trace-level hex dump of the outgoing broadcast to this client (first 256 bytes)
-                    {
-                        let dump_len = std::cmp::min(256, update_data.len());
-                        if dump_len > 0 {
-                            let dump = update_data[..dump_len]
-                                .iter()
-                                .map(|b| format!("{:02x}", b))
-                                .collect::<Vec<_>>()
-                                .join(" ");
-                            tracing::trace!(
-                                "Outgoing per-client broadcast payload hex (first {} bytes): {}",
-                                dump_len,
-                                dump
-                            );
-                        } else {
-                            tracing::trace!("Outgoing per-client broadcast payload is empty");
-                        }
+                };
+                tracing::info!("Got broadcast update for {}: {} bytes", guid, update_data.len());
+
+                // Trace-level hex dump of the outgoing broadcast to this client (first 256 bytes)
+                {
+                    let dump_len = std::cmp::min(256, update_data.len());
+                    if dump_len > 0 {
+                        let dump = update_data[..dump_len]
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        tracing::trace!(
+                            "Outgoing per-client broadcast payload hex (first {} bytes): {}",
+                            dump_len,
+                            dump
+                        );
+                    } else {
+                        tracing::trace!("Outgoing per-client broadcast payload is empty");
                     }
+                }
 
-                    if let Err(e) = sender.send(Message::Binary(Bytes::from(update_data))).await {
-                        tracing::error!("Failed to send broadcast: {}", e);
-                        break;
+                if let Err(e) = session.send(&guid, update_data).await {
+                    tracing::error!("Failed to send broadcast: {}", e);
+                    break;
+                }
+            }
+
+            Some(message) = vault_messages.next() => {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        tracing::warn!("Vault listener missed {} messages", skipped);
+                        continue;
                     }
+                };
+                // Subscribers get the same message through the document's channel
+                if session.subscriptions.contains_key(&message.guid) {
+                    continue;
+                }
+                if let Err(e) = session.send(&message.guid, message.payload).await {
+                    tracing::error!("Failed to send vault message: {}", e);
+                    break;
+                }
+            }
+
+            // Re-check the role when membership or ownership of the vault changes
+            Some(change) = access_changes.next() => {
+                // Missed changes might have concerned us, so re-check after a lag too
+                if let Ok(change) = change
+                    && !change.affects(session.vault_id, session.user_id)
+                {
+                    continue;
+                }
+
+                if !session.recheck_access().await {
+                    break;
                 }
             }
         }
     }
 
+    session.close().await;
+    sync_manager.metrics().session_closed();
+
     tracing::info!("WebSocket connection closed");
 }
 
-async fn handle_binary_message(
-    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    state: &AppState,
-    data: &[u8],
-    subscriptions: &mut HashMap<String, broadcast::Receiver<Vec<u8>>>,
-    doc: &Option<String>,
+/// State of one authenticated WebSocket connection
+struct Session {
+    sender: SplitSink<WebSocket, Message>,
+    state: AppState,
+    addressing: Addressing,
+    /// Documents this client is subscribed to
+    subscriptions: Subscriptions,
+    synced: SyncedStates,
     vault_id: Uuid,
     user_id: Uuid,
     role: VaultRole,
     session_id: Uuid,
-) -> anyhow::Result<()> {
-    // Yjs y-protocols message format:
-    // varUint(syncProtocolMessageType) • varUint(messageType) • varByteArray(payload)
-    // where syncProtocolMessageType := 0 for sync protocol
-    // messageType := 0 (Sync Step 1), 1 (Sync Step 2), 2 (Update)
-    // Message types:
-    // 0 = Sync Step 1 (client sends state vector)
-    // 1 = Sync Step 2 (server sends missing updates)
-    // 2 = Update (incremental update)
-    // 3 = Awareness (cursor position, etc)
+    /// When the access token expires (unix seconds)
+    expires_at: i64,
+    /// Whether the client has been told that its token is about to expire
+    expiry_warned: bool,
+    update_rate: RateLimiter,
+    /// Messages recently refused for exceeding a limit; too many close the session
+    violations: ViolationWindow,
+}
 
-    if data.is_empty() {
-        tracing::warn!("handle_binary_message called with empty data");
-        return Ok(());
+impl Session {
+    /// Send a message about `guid`, prefixing it with the guid on multiplexed connections
+    async fn send(&mut self, guid: &str, msg: Vec<u8>) -> anyhow::Result<()> {
+        self.sender.send(frame(&self.addressing, guid, msg)).await?;
+        Ok(())
     }
 
-    tracing::debug!(
-        "Raw message bytes (first 10): {:?}",
-        &data[..std::cmp::min(10, data.len())]
-    );
+    /// Send a connection-level control message
+    async fn send_control(&mut self, control: ControlMessage) -> anyhow::Result<()> {
+        self.send("", Extension::Control(control).encode()).await
+    }
 
-    let (protocol_type, rest1) = read_var_from_slice(data)?;
-    tracing::info!(
-        "Decoded protocol_type: {}, remaining bytes: {}",
-        protocol_type,
-        rest1.len()
-    );
+    /// When the session next has to act on its token: first to warn about the
+    /// upcoming expiry, then to close the session once it has expired
+    fn token_deadline(&self) -> tokio::time::Instant {
+        let mut deadline_ms = self.expires_at * 1000;
+        if !self.expiry_warned {
+            deadline_ms -= TOKEN_EXPIRY_WARNING.as_millis() as i64;
+        }
+        let remaining_ms = (deadline_ms - chrono::Utc::now().timestamp_millis()).max(0);
+        tokio::time::Instant::now() + tokio::time::Duration::from_millis(remaining_ms as u64)
+    }
 
-    if protocol_type == 0 {
-        // Sync protocol message
-        let (msg_type, rest2) = read_var_from_slice(rest1)?;
-        tracing::debug!(
-            "Decoded msg_type: {}, remaining bytes: {}",
-            msg_type,
-            rest2.len()
-        );
+    async fn handle_control(&mut self, control: ControlMessage) -> anyhow::Result<()> {
+        match control {
+            ControlMessage::Auth { token } => self.reauthenticate(&token).await,
+            other => {
+                tracing::warn!("Unexpected control message from client: {:?}", other);
+                Ok(())
+            }
+        }
+    }
 
-        // Now read the payload as varByteArray (length prefixed)
-        let (payload_len, payload_start) = read_var_from_slice(rest2)?;
-        tracing::debug!(
-            "Decoded payload_len: {}, remaining bytes: {}",
-            payload_len,
-            payload_start.len()
-        );
-        let payload_len = payload_len as usize;
+    /// Replace the session's access token with a fresh one for the same user
+    async fn reauthenticate(&mut self, token: &str) -> anyhow::Result<()> {
+        let reply = match validate_token(token, &self.state.config.jwt_secret) {
+            Ok(claims) if claims.sub == self.user_id => {
+                self.expires_at = claims.exp as i64;
+                self.expiry_warned = false;
+                tracing::info!(
+                    "Session {} re-authenticated until {}",
+                    self.session_id,
+                    self.expires_at
+                );
+                ControlMessage::Authenticated {
+                    expires_at: self.expires_at,
+                }
+            }
+            Ok(claims) => {
+                tracing::warn!(
+                    "Session {} of user {} tried to re-authenticate as {}",
+                    self.session_id,
+                    self.user_id,
+                    claims.sub
+                );
+                ControlMessage::AuthFailed {
+                    reason: "token belongs to another user".to_string(),
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Rejected re-auth token: {:?}", e);
+                ControlMessage::AuthFailed {
+                    reason: "invalid token".to_string(),
+                }
+            }
+        };
+        self.send_control(reply).await
+    }
 
-        if payload_start.len() < payload_len {
-            tracing::warn!(
-                "Message payload length mismatch: declared={}, available={}",
-                payload_len,
-                payload_start.len()
-            );
+    /// Tell the client why its message about `guid` was refused
+    async fn send_error(
+        &mut self,
+        guid: &str,
+        code: ErrorCode,
+        message: String,
+    ) -> anyhow::Result<()> {
+        self.send(
+            guid,
+            Extension::Error(ErrorFrame { code, message }).encode(),
+        )
+        .await
+    }
+
+    /// Work out which document a frame is for and dispatch it
+    async fn handle_frame(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let (guid, body) = match &self.addressing {
+            Addressing::Single(Some(guid)) => (guid.clone(), data),
+            Addressing::Single(None) => {
+                tracing::warn!("Message received without document guid in path");
+                return Ok(());
+            }
+            Addressing::Multiplexed => match read_guid(data) {
+                Ok((guid, body)) => (guid.to_string(), body),
+                Err(e) => {
+                    return self
+                        .send_error("", ErrorCode::Malformed, e.to_string())
+                        .await;
+                }
+            },
+        };
+
+        if matches!(self.addressing, Addressing::Multiplexed) && body.is_empty() {
+            self.unsubscribe(&guid).await;
             return Ok(());
         }
 
-        let payload = &payload_start[..payload_len];
+        let max_frame_bytes = self.state.config.sync.max_frame_bytes;
+        let result = if body.len() > max_frame_bytes {
+            Err(LimitExceeded::FrameSize {
+                size: body.len(),
+                max: max_frame_bytes,
+            }
+            .into())
+        } else if guid.is_empty() {
+            self.handle_connection_message(body).await
+        } else {
+            self.handle_binary_message(&guid, body).await
+        };
+        let Err(e) = result else {
+            return Ok(());
+        };
+        let Some(code) = error_code(&e) else {
+            return Err(e);
+        };
+
+        tracing::warn!("Refusing message for document {}: {:?}", guid, e);
+        if let Some(limit) = e.downcast_ref::<LimitExceeded>() {
+            self.state.sync_manager.metrics().limit_exceeded(limit);
+            self.violations.record();
+        }
+        self.send_error(&guid, code, e.to_string()).await?;
+        if code != ErrorCode::DocNotFound && !guid.is_empty() {
+            // The client may already have applied the edit that was refused
+            self.send_server_state(&guid).await?;
+        }
+        Ok(())
+    }
 
+    async fn handle_binary_message(&mut self, guid: &str, data: &[u8]) -> anyhow::Result<()> {
         tracing::debug!(
-            "handle_binary_message: protocol_type={} msg_type={} total_len={} payload_len={}",
-            protocol_type,
-            msg_type,
-            data.len(),
-            payload.len()
+            "Raw message bytes (first 10): {:?}",
+            &data[..std::cmp::min(10, data.len())]
         );
 
-        match msg_type {
-            0 => {
-                // Sync Step 1: Client sends state vector
-                handle_sync_step1(sender, state, payload, subscriptions, doc, vault_id).await?;
+        // A frame may carry several messages back to back
+        for message in decode_frame(data)? {
+            self.handle_message(guid, message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a frame addressed to the connection itself (the empty guid on `/ws`),
+    /// which takes control messages only
+    async fn handle_connection_message(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        for message in decode_frame(data)? {
+            let YMessage::Custom(tag, data) = message else {
+                return Err(ProtocolError::MissingGuid.into());
+            };
+            let Extension::Control(control) = Extension::from_custom(tag, &data)? else {
+                return Err(ProtocolError::MissingGuid.into());
+            };
+            self.handle_control(control).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, guid: &str, message: YMessage) -> anyhow::Result<()> {
+        match message {
+            YMessage::Sync(SyncMessage::SyncStep1(state_vector)) => {
+                self.handle_sync_step1(guid, state_vector).await?;
             }
-            1 => {
-                // Sync Step 2 is normally server -> client only. If we receive a msg_type=1 from a client,
-                // it's likely a misrouted message. Log and ignore.
-                tracing::warn!(
-                    "Received msg_type=1 from client (Sync Step 2); payload_len={}",
-                    payload.len()
+            // Sync Step 2 answers our own sync step 1 with whatever the client has that
+            // we don't (e.g. offline edits); it is applied exactly like an update
+            YMessage::Sync(SyncMessage::SyncStep2(update) | SyncMessage::Update(update)) => {
+                self.handle_update(guid, &update).await?;
+            }
+            YMessage::Awareness(update) => {
+                tracing::debug!(
+                    "Received awareness message ({} clients)",
+                    update.clients.len()
                 );
+                self.handle_awareness(guid, update).await?;
             }
-            2 => {
-                // Update: Client sends incremental update
-                handle_update(
-                    sender, state, payload, doc, vault_id, user_id, role, session_id,
-                )
-                .await?;
+            YMessage::AwarenessQuery => {
+                self.handle_awareness_query(guid).await?;
             }
-            _ => {
-                tracing::warn!("Unknown sync message type: {}", msg_type);
+            YMessage::Auth(_) => {
+                // Clients authenticate with the token, not with y-protocols auth messages
+                tracing::warn!("Ignoring auth message from client");
             }
+            YMessage::Custom(tag, data) => match Extension::from_custom(tag, &data)? {
+                Extension::Control(control) => {
+                    self.handle_control(control).await?;
+                }
+                Extension::MetadataPatch(json) => {
+                    self.handle_metadata_patch(guid, &json).await?;
+                }
+                Extension::Metadata(_) | Extension::Error(_) => {
+                    tracing::warn!("Ignoring server-only message from client");
+                }
+            },
         }
-    } else if protocol_type == 1 {
-        // Awareness protocol message
-        tracing::debug!("Received awareness message, rest1 len: {}", rest1.len());
-        tracing::debug!(
-            "Awareness raw bytes (first 20): {:?}",
-            &rest1[..std::cmp::min(20, rest1.len())]
-        );
 
-        let (payload_len, payload_start) = read_var_from_slice(rest1)?;
-        let payload_len = payload_len as usize;
+        Ok(())
+    }
 
-        tracing::debug!(
-            "Awareness payload_len: {}, remaining: {}",
-            payload_len,
-            payload_start.len()
+    async fn handle_sync_step1(
+        &mut self,
+        guid: &str,
+        state_vector: StateVector,
+    ) -> anyhow::Result<()> {
+        tracing::info!(
+            "Sync step 1 for document: {} in vault {} (state_vector {:?})",
+            guid,
+            self.vault_id,
+            state_vector
         );
 
-        if payload_start.len() < payload_len {
-            tracing::warn!(
-                "Awareness payload length mismatch: declared={}, available={}",
-                payload_len,
-                payload_start.len()
-            );
-            return Ok(());
+        // Subscribe before encoding the diff so no update falls between the two
+        let newly_subscribed = !self.subscriptions.contains_key(guid);
+        if newly_subscribed {
+            let receiver = self.state.sync_manager.subscribe(guid).await;
+            self.subscriptions
+                .insert(guid.to_string(), BroadcastStream::new(receiver));
         }
 
-        let payload = &payload_start[..payload_len];
-        tracing::debug!(
-            "Awareness payload (first 32 bytes): {:?}",
-            &payload[..std::cmp::min(32, payload.len())]
+        // Load document (verifies it belongs to this vault) and compute the diff for the client
+        let diff = match self
+            .state
+            .sync_manager
+            .encode_diff(guid, self.vault_id, state_vector.clone())
+            .await
+        {
+            Ok(diff) => diff,
+            Err(e) => {
+                if newly_subscribed {
+                    self.subscriptions.remove(guid);
+                }
+                return Err(e);
+            }
+        };
+        tracing::info!(
+            "Sending diff: {} bytes (client state_vector: {:?}), doc_type: {}",
+            diff.update.len(),
+            &state_vector,
+            diff.metadata.doc_type
         );
-        handle_awareness(sender, state, payload).await?;
-    } else {
-        tracing::warn!("Unknown protocol type: {}", protocol_type);
-    }
 
-    Ok(())
-}
+        // Send metadata to client
+        let metadata_msg = Extension::Metadata(serde_json::to_string(&diff.metadata)?).encode();
 
-async fn handle_sync_step1(
-    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    state: &AppState,
-    payload: &[u8],
-    subscriptions: &mut HashMap<String, broadcast::Receiver<Vec<u8>>>,
-    doc: &Option<String>,
-    vault_id: Uuid,
-) -> anyhow::Result<()> {
-    // Payload is the state vector (already decoded as varByteArray by caller)
-    let state_vector_bytes = payload;
+        // Send Sync Step 2 back to client
+        let response = YMessage::Sync(SyncMessage::SyncStep2(diff.update)).encode_v1();
 
-    // When using path-based doc id, use it directly
-    let guid = match doc.as_ref() {
-        Some(path_guid) => path_guid.clone(),
-        None => {
-            tracing::warn!("Sync step 1 received without document guid in path");
-            return Ok(());
+        // Log the response we're sending
+        let hex_dump = response[..std::cmp::min(64, response.len())]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        tracing::info!(
+            "Sending SyncStep2: {} bytes total, first 64 bytes hex: {}",
+            response.len(),
+            hex_dump
+        );
+
+        self.send(guid, response).await?;
+
+        // Send metadata to client
+        self.send(guid, metadata_msg).await?;
+
+        if newly_subscribed {
+            tracing::debug!("Client subscribed to document: {}", guid);
+            self.state
+                .sync_manager
+                .set_document_presence(self.session_id, guid, true);
+
+            // Let the new client see who else is already in the document
+            if let Some(snapshot) = self.state.sync_manager.awareness_snapshot(guid).await {
+                self.send(guid, YMessage::Awareness(snapshot).encode_v1())
+                    .await?;
+            }
         }
-    };
 
-    tracing::info!(
-        "Sync step 1 for document: {} in vault {} (state_vector {} bytes)",
-        guid,
-        vault_id,
-        state_vector_bytes.len()
-    );
+        // Send our own Sync Step 1 so the client answers with anything we are missing
+        let server_step1 =
+            YMessage::Sync(SyncMessage::SyncStep1(diff.state_vector.clone())).encode_v1();
+        self.send(guid, server_step1).await?;
 
-    // Decode state vector
-    let state_vector = StateVector::decode_v1(state_vector_bytes)?;
+        self.synced.insert(guid.to_string(), diff.state_vector);
 
-    // Load document from database (verifies it belongs to this vault)
-    let (doc_obj, metadata) = load_or_create_document(state, &guid, vault_id).await?;
+        // The client may read a document in the trash, but not edit it
+        if diff.deleted {
+            self.send(
+                guid,
+                Extension::Control(ControlMessage::DocDeleted).encode(),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
 
-    // Send diff to client
-    let update = {
-        let txn = doc_obj.transact();
-        let diff = txn.encode_diff_v1(&state_vector);
+    /// Bring a client that missed broadcasts back up to date by sending everything
+    /// since the state it was last fully synced to, plus the current awareness states
+    async fn resync_lagged(&mut self, guid: &str) -> anyhow::Result<()> {
+        let since = self.synced.get(guid).cloned().unwrap_or_default();
+        let diff = self
+            .state
+            .sync_manager
+            .encode_diff(guid, self.vault_id, since)
+            .await?;
         tracing::info!(
-            "Sending diff: {} bytes (client state_vector: {:?}), doc_type: {}",
-            diff.len(),
-            &state_vector,
-            metadata.doc_type
+            "Resyncing lagged client on document {} with {} bytes",
+            guid,
+            diff.update.len()
         );
-        diff
-    };
-
-    // Send metadata to client (protocol type 2)
-    let metadata_json = serde_json::to_string(&metadata)?;
-    let mut metadata_msg = Vec::new();
-    encode_var_uint(&mut metadata_msg, 2)?; // Metadata protocol marker
-    encode_var_uint(&mut metadata_msg, 1)?; // Message type (Server -> Client metadata)
-    encode_var_uint(&mut metadata_msg, metadata_json.len() as u32)?; // Payload length
-    metadata_msg.extend_from_slice(metadata_json.as_bytes());
-
-    // Send Sync Step 2 back to client
-    // Format: varUint(0) • varUint(1) • varByteArray(update)
-    let mut response = Vec::new();
-    encode_var_uint(&mut response, 0)?; // Sync protocol marker
-    encode_var_uint(&mut response, 1)?; // Message type (Sync Step 2)
-    encode_var_uint(&mut response, update.len() as u32)?; // Payload length
-    response.extend_from_slice(&update);
-
-    // Log the response we're sending
-    let hex_dump = response[..std::cmp::min(64, response.len())]
-        .iter()
-        .map(|b| format!("{:02x}", b))
-        .collect::<Vec<_>>()
-        .join(" ");
-    tracing::info!(
-        "Sending SyncStep2: {} bytes total, first 64 bytes hex: {}",
-        response.len(),
-        hex_dump
-    );
 
-    sender.send(Message::Binary(Bytes::from(response))).await?;
-
-    // Send metadata to client
-    sender
-        .send(Message::Binary(Bytes::from(metadata_msg)))
+        self.send(
+            guid,
+            YMessage::Sync(SyncMessage::SyncStep2(diff.update)).encode_v1(),
+        )
         .await?;
+        if let Some(snapshot) = self.state.sync_manager.awareness_snapshot(guid).await {
+            self.send(guid, YMessage::Awareness(snapshot).encode_v1())
+                .await?;
+        }
 
-    // Subscribe this client to document updates
-    if !subscriptions.contains_key(&guid) {
-        let receiver = state.sync_manager.subscribe(&guid).await;
-        subscriptions.insert(guid.clone(), receiver);
-        tracing::debug!("Client subscribed to document: {}", guid);
+        self.synced.insert(guid.to_string(), diff.state_vector);
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Send the full server state of a subscribed document after refusing an edit to it,
+    /// so the client can replace whatever it already applied locally
+    async fn send_server_state(&mut self, guid: &str) -> anyhow::Result<()> {
+        if !self.subscriptions.contains_key(guid) {
+            return Ok(());
+        }
 
-async fn handle_update(
-    _sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    state: &AppState,
-    payload: &[u8],
-    doc: &Option<String>,
-    vault_id: Uuid,
-    user_id: Uuid,
-    role: VaultRole,
-    session_id: Uuid,
-) -> anyhow::Result<()> {
-    tracing::debug!("handle_update called with payload {} bytes", payload.len());
+        let diff = self
+            .state
+            .sync_manager
+            .encode_diff(guid, self.vault_id, StateVector::default())
+            .await?;
+        self.send(
+            guid,
+            YMessage::Sync(SyncMessage::SyncStep2(diff.update)).encode_v1(),
+        )
+        .await?;
 
-    // Block viewers from sending updates (read-only access)
-    if role == VaultRole::Viewer {
-        tracing::warn!(
-            "User {} attempted to edit document in vault {} as viewer - rejecting update",
-            user_id,
-            vault_id
-        );
-        return Ok(()); // Silently ignore the update
+        self.synced.insert(guid.to_string(), diff.state_vector);
+        Ok(())
     }
 
-    // Payload is the update (already decoded as varByteArray by caller)
-    let update_bytes = payload;
+    async fn handle_update(&mut self, guid: &str, payload: &[u8]) -> anyhow::Result<()> {
+        tracing::debug!("handle_update called with payload {} bytes", payload.len());
 
-    // When using path-based doc id, use it directly
-    let guid = match doc.as_ref() {
-        Some(path_guid) => path_guid.clone(),
-        None => {
-            tracing::warn!("Update received without document guid in path");
+        // Clients answer our sync step 1 even when they have nothing we lack; that reply
+        // is no edit, so it is neither refused nor rate limited
+        if Update::decode_v1(payload)?.is_empty() {
+            tracing::debug!("Update for {} contained no changes", guid);
             return Ok(());
         }
-    };
 
-    tracing::info!(
-        "Incoming update for document: {} ({} bytes) from user {} in session {}",
-        guid,
-        update_bytes.len(),
-        user_id,
-        session_id
-    );
+        // Block viewers from sending updates (read-only access)
+        if self.role == VaultRole::Viewer {
+            tracing::warn!(
+                "User {} attempted to edit document in vault {} as viewer - rejecting update",
+                self.user_id,
+                self.vault_id
+            );
+            self.send_error(
+                guid,
+                ErrorCode::ReadOnly,
+                "Viewers cannot edit documents in this vault".to_string(),
+            )
+            .await?;
+            return self.send_server_state(guid).await;
+        }
 
-    // Load document from database
-    let (doc_obj, metadata) = load_or_create_document(state, &guid, vault_id).await?;
+        self.update_rate.check()?;
 
-    // Extract full document content BEFORE applying update
-    let content_before = extract_text_sample(&doc_obj, usize::MAX);
+        // Payload is the update (already decoded as varByteArray by caller)
+        let update_bytes = payload;
 
-    // Apply update to document
-    let update = Update::decode_v1(update_bytes)?;
-    {
-        let mut txn = doc_obj.transact_mut();
-        txn.apply_update(update)?;
-    }
+        tracing::info!(
+            "Incoming update for document: {} ({} bytes) from user {} in session {}",
+            guid,
+            update_bytes.len(),
+            self.user_id,
+            self.session_id
+        );
 
-    // Extract full document content AFTER applying update
-    let content_after = extract_text_sample(&doc_obj, usize::MAX);
+        // Apply update to the live document; persistence happens in the background
+        let applied = match self
+            .state
+            .sync_manager
+            .apply_update(guid, self.vault_id, self.user_id, update_bytes)
+            .await
+        {
+            Err(e) if e.is::<DocumentDeleted>() && self.restores_on_edit().await? => {
+                tracing::info!(
+                    "User {} restored document {} from the trash by editing it",
+                    self.user_id,
+                    guid
+                );
+                self.state
+                    .sync_manager
+                    .restore_document(guid, self.vault_id)
+                    .await?;
+                self.state
+                    .sync_manager
+                    .apply_update(guid, self.vault_id, self.user_id, update_bytes)
+                    .await?
+            }
+            result => result?,
+        };
+        let Some(applied) = applied else {
+            tracing::debug!("Update for {} contained no changes", guid);
+            return Ok(());
+        };
+        self.state.sync_manager.metrics().update_applied();
+
+        // The vault's creation policy allowed this update to create the document
+        if applied.created {
+            log_document_created(
+                &self.state.pool,
+                guid,
+                self.user_id,
+                Some(self.session_id),
+                update_bytes,
+            )
+            .await?;
+        }
 
-    // Log this edit to audit log with before/after content
-    log_document_edit(
-        state,
-        &guid,
-        user_id,
-        session_id,
-        update_bytes,
-        content_before,
-        content_after,
-    )
-    .await?;
+        // Log this edit to audit log with before/after content
+        log_document_edit(
+            &self.state,
+            guid,
+            self.user_id,
+            self.session_id,
+            update_bytes,
+            applied.content_before,
+            applied.content_after,
+        )
+        .await?;
 
-    // Save updated document to database, preserving doc_type
-    save_document(
-        state,
-        &guid,
-        &doc_obj,
-        vault_id,
-        user_id,
-        &metadata.doc_type,
-    )
-    .await?;
+        // Broadcast update to other clients, on this and every other instance
+        if let Err(e) = self
+            .state
+            .sync_manager
+            .publish_update(guid, self.session_id, update_bytes)
+            .await
+        {
+            tracing::error!("Failed to broadcast update: {:?}", e);
+        }
 
-    // Broadcast update to other clients
-    // Format: varUint(0) • varUint(2) • varByteArray(update)
-    let mut broadcast_msg = Vec::new();
-    encode_var_uint(&mut broadcast_msg, 0)?; // Sync protocol marker
-    encode_var_uint(&mut broadcast_msg, 2)?; // Message type 2 = Update
-    encode_var_uint(&mut broadcast_msg, update_bytes.len() as u32)?; // Payload length
-    broadcast_msg.extend_from_slice(update_bytes);
+        Ok(())
+    }
 
-    if let Err(e) = state
-        .sync_manager
-        .broadcast_update(&guid, broadcast_msg)
-        .await
-    {
-        tracing::error!("Failed to broadcast update: {}", e);
+    /// Whether an edit by this session takes a document out of the trash instead of
+    /// being refused: owners can opt into that per vault
+    async fn restores_on_edit(&self) -> anyhow::Result<bool> {
+        if self.role != VaultRole::Owner {
+            return Ok(false);
+        }
+        let restore_on_edit = sqlx::query_scalar!(
+            "SELECT restore_on_edit FROM vaults WHERE id = $1",
+            self.vault_id
+        )
+        .fetch_optional(&self.state.pool)
+        .await?;
+        Ok(restore_on_edit.unwrap_or(false))
     }
 
-    Ok(())
-}
+    async fn handle_metadata_patch(&mut self, guid: &str, json: &str) -> anyhow::Result<()> {
+        let patch: DocumentMetadataPatch = serde_json::from_str(json)
+            .map_err(|e| ProtocolError::InvalidJson("metadata patch", e.to_string()))?;
 
-async fn handle_awareness(
-    _sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    _state: &AppState,
-    payload: &[u8],
-) -> anyhow::Result<()> {
-    // TODO: Broadcast awareness to other connected clients
-    tracing::debug!("Awareness update ({} bytes)", payload.len());
-    Ok(())
-}
+        if self.role == VaultRole::Viewer {
+            tracing::warn!(
+                "User {} attempted to change metadata of {} as viewer - rejecting patch",
+                self.user_id,
+                guid
+            );
+            return self
+                .send_error(
+                    guid,
+                    ErrorCode::ReadOnly,
+                    "Viewers cannot change document metadata in this vault".to_string(),
+                )
+                .await;
+        }
 
-// Load document from database, or create new one if doesn't exist
-// Verifies document belongs to the specified vault
-async fn load_or_create_document(
-    state: &AppState,
-    guid: &str,
-    vault_id: Uuid,
-) -> anyhow::Result<(Doc, DocumentMetadata)> {
-    // Try to load from database, verifying it belongs to this vault
-    let result = sqlx::query!(
-        r#"
-        SELECT
-            s.yjs_state,
-            s.created_at,
-            s.modified_at,
-            s.parent_guid,
-            s.doc_type,
-            m.title,
-            m.icon,
-            m.description,
-            COALESCE(m.tags, '{}') as tags
-        FROM subdocs s
-        LEFT JOIN subdoc_metadata m ON s.guid = m.subdoc_guid
-        WHERE s.guid = $1 AND s.vault_id = $2 AND s.deleted_at IS NULL
-        "#,
-        guid,
-        vault_id
-    )
-    .fetch_optional(&state.pool)
-    .await?;
+        // Subscribers, including this client, receive the result as a metadata message
+        let metadata = self
+            .state
+            .sync_manager
+            .update_metadata(guid, self.vault_id, &patch)
+            .await?;
+        tracing::info!(
+            "User {} updated metadata of document {} (title: {})",
+            self.user_id,
+            guid,
+            metadata.title
+        );
+        Ok(())
+    }
 
-    if let Some(record) = result {
-        // Document exists and belongs to this vault, load it
-        let doc = Doc::new();
-        let update = Update::decode_v1(&record.yjs_state)?;
+    async fn handle_awareness(
+        &mut self,
+        guid: &str,
+        update: AwarenessUpdate,
+    ) -> anyhow::Result<()> {
+        // Only clients that completed sync step 1 (and thus passed the vault check) may announce themselves
+        if !self.subscriptions.contains_key(guid) {
+            tracing::warn!(
+                "Ignoring awareness for document {} before sync step 1 (session {})",
+                guid,
+                self.session_id
+            );
+            return Ok(());
+        }
+
+        let Some(changed) = self
+            .state
+            .sync_manager
+            .apply_awareness(guid, self.session_id, update)
+            .await
+        else {
+            tracing::debug!("Awareness update for {} contained no newer states", guid);
+            return Ok(());
+        };
+
+        if let Err(e) = self
+            .state
+            .sync_manager
+            .publish_awareness(guid, self.session_id, changed)
+            .await
         {
-            let mut txn = doc.transact_mut();
-            txn.apply_update(update)?;
+            tracing::error!("Failed to broadcast awareness: {:?}", e);
         }
 
-        let tags = record.tags.unwrap_or_default();
+        Ok(())
+    }
 
-        let metadata = DocumentMetadata {
-            guid: guid.to_string(),
-            vault_id,
-            title: record.title,
-            doc_type: record.doc_type,
-            icon: record.icon,
-            description: record.description,
-            tags,
-            parent_guid: record.parent_guid,
-            created_at: record.created_at,
-            modified_at: record.modified_at,
-        };
+    /// Answer a client asking for the awareness states of everyone in the document
+    async fn handle_awareness_query(&mut self, guid: &str) -> anyhow::Result<()> {
+        if !self.subscriptions.contains_key(guid) {
+            tracing::warn!(
+                "Ignoring awareness query for document {} before sync step 1 (session {})",
+                guid,
+                self.session_id
+            );
+            return Ok(());
+        }
 
-        tracing::debug!("Loaded document {} from vault {}", guid, vault_id);
-        Ok((doc, metadata))
-    } else {
-        // Document doesn't exist, create new one with default metadata
-        let doc = Doc::new();
-        let now = Utc::now();
+        let snapshot = self
+            .state
+            .sync_manager
+            .awareness_snapshot(guid)
+            .await
+            .unwrap_or_else(|| AwarenessUpdate {
+                clients: HashMap::new(),
+            });
+        self.send(guid, YMessage::Awareness(snapshot).encode_v1())
+            .await
+    }
 
-        let metadata = DocumentMetadata {
-            guid: guid.to_string(),
-            vault_id,
-            title: "Untitled".to_string(),
-            doc_type: "document".to_string(),
-            icon: None,
-            description: None,
-            tags: vec![],
-            parent_guid: None,
-            created_at: now,
-            modified_at: now,
-        };
+    /// Stop syncing a document on this connection
+    async fn unsubscribe(&mut self, guid: &str) {
+        // Drop our receiver first so the sync manager sees the updated subscriber count
+        if self.subscriptions.remove(guid).is_none() {
+            return;
+        }
+        self.synced.remove(guid);
+        self.state
+            .sync_manager
+            .set_document_presence(self.session_id, guid, false);
+        tracing::debug!("Client unsubscribed from document: {}", guid);
+
+        // Tell the remaining collaborators that this client's cursors are gone
+        if let Some(removal) = self
+            .state
+            .sync_manager
+            .remove_awareness(guid, self.session_id)
+            .await
+            && let Err(e) = self
+                .state
+                .sync_manager
+                .publish_awareness(guid, self.session_id, removal)
+                .await
+        {
+            tracing::error!("Failed to broadcast awareness removal: {:?}", e);
+        }
 
-        tracing::debug!("Created new document {} for vault {}", guid, vault_id);
-        Ok((doc, metadata))
+        self.state.sync_manager.release(guid).await;
     }
-}
 
-/// Extract basic metadata from a Yjs update
-///
-/// This provides a best-effort extraction of edit metadata for audit logging.
-/// Yjs updates are complex CRDT structures with limited public introspection APIs.
-///
-/// We extract:
-/// - Update size (bytes changed)
-/// - Current document text sample (for context)
-///
-/// For detailed operation-level diffs, see:
-/// - https://docs.yjs.dev/ecosystem/editor-bindings/prosemirror
-/// - https://github.com/yjs/yjs-demos/tree/main/prosemirror-versions
-fn extract_edit_metadata(yjs_update: &[u8], doc: &Doc) -> EditMetadata {
-    // Verify the update can be decoded
-    let update_result = Update::decode_v1(yjs_update);
-
-    if update_result.is_ok() {
-        // Classify based on update size (heuristic)
-        // Small updates are likely formatting, larger ones are content changes
-        let edit_type = match yjs_update.len() {
-            0..=50 => "format".to_string(),   // Small updates likely formatting
-            51..=500 => "insert".to_string(), // Medium updates likely insertions
-            _ => "update".to_string(),        // Large updates are general edits
+    /// Look the user's role up again after an access change. Downgrades take
+    /// effect for the next message; losing access closes the socket, in which
+    /// case this returns `false`.
+    async fn recheck_access(&mut self) -> bool {
+        let role = match get_user_vault_role(&self.state.pool, self.vault_id, self.user_id).await {
+            Ok(role) => role,
+            Err(e) => {
+                // Keep the current role rather than dropping the client over a hiccup
+                tracing::error!("Failed to re-check vault role: {:?}", e);
+                return true;
+            }
         };
 
-        // Extract current document text for context
-        let content_sample = extract_text_sample(doc, 100);
-
-        EditMetadata {
-            edit_type,
-            block_type: Some("prosemirror".to_string()),
-            block_position: None,
-            content_before: None,
-            content_after: content_sample,
+        if role == self.role {
+            return true;
         }
-    } else {
-        // If we can't decode the update, log it as unknown
-        tracing::warn!("Failed to decode Yjs update for metadata extraction");
-        EditMetadata {
-            edit_type: "unknown".to_string(),
-            block_type: None,
-            block_position: None,
-            content_before: None,
-            content_after: None,
+
+        tracing::info!(
+            "Vault role of user {} on vault {} changed from {:?} to {:?} (session_id={})",
+            self.user_id,
+            self.vault_id,
+            self.role,
+            role,
+            self.session_id
+        );
+
+        if role == VaultRole::None {
+            let _ = self
+                .sender
+                .send(permission_denied(&self.addressing, "vault access revoked"))
+                .await;
+            let _ = self
+                .sender
+                .send(close_message(CLOSE_ACCESS_REVOKED, "vault access revoked"))
+                .await;
+            return false;
         }
-    }
-}
 
-/// Extract a text sample from the document for audit context
-/// This is a best-effort extraction that gets the first N characters
-fn extract_text_sample(doc: &Doc, max_chars: usize) -> Option<String> {
-    let txn = doc.transact();
+        self.role = role;
+        true
+    }
 
-    // Tiptap's Collaboration extension uses "default" as the field name by default
-    if let Some(xml_fragment) = txn.get_xml_fragment("default") {
-        // Return raw XML for proper rendering on frontend
-        let xml_text = xml_fragment.get_string(&txn);
-        if !xml_text.is_empty() {
-            let sample = xml_text.chars().take(max_chars).collect::<String>();
-            return Some(sample);
+    /// Unsubscribe from every document once the connection is gone
+    async fn close(mut self) {
+        let guids: Vec<String> = self.subscriptions.keys().cloned().collect();
+        for guid in guids {
+            self.unsubscribe(&guid).await;
         }
     }
+}
 
-    None
+/// Error frame code for failures the client caused, `None` for server-side failures
+fn error_code(error: &anyhow::Error) -> Option<ErrorCode> {
+    if let Some(limit) = error.downcast_ref::<LimitExceeded>() {
+        Some(match limit {
+            LimitExceeded::UpdateRate { .. } => ErrorCode::RateLimited,
+            LimitExceeded::FrameSize { .. } | LimitExceeded::DocumentSize { .. } => {
+                ErrorCode::TooLarge
+            }
+        })
+    } else if error.is::<DocumentNotFound>() {
+        Some(ErrorCode::DocNotFound)
+    } else if error.is::<DocumentDeleted>() {
+        Some(ErrorCode::DocDeleted)
+    } else if error.is::<ProtocolError>() || error.is::<yrs::encoding::read::Error>() {
+        Some(ErrorCode::Malformed)
+    } else {
+        None
+    }
 }
 
 // Log document edit to audit log
@@ -876,76 +1381,1285 @@ fn classify_edit_type(
     }
 }
 
-// Save document to database
-async fn save_document(
-    state: &AppState,
-    guid: &str,
-    doc: &Doc,
-    vault_id: Uuid,
-    user_id: Uuid,
-    doc_type: &str,
-) -> anyhow::Result<()> {
-    tracing::debug!(
-        "save_document called for guid: {}, vault_id: {}, user_id: {}, doc_type: {}",
-        guid,
-        vault_id,
-        user_id,
-        doc_type
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{AccessChange, SyncConfig, TrashChange};
+    use crate::test_support::{app_state, create_user, create_vault, serve, token_for};
+    use axum::{Router, routing::get};
+    use sqlx::PgPool;
+    use tokio::net::TcpStream;
+    use tokio::time::{Duration, timeout};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite};
+    use yrs::updates::decoder::Decode;
+    use yrs::{Doc, GetString, ReadTxn, Text, Transact, Update};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// The server extension a frame carries, if it is one
+    fn extension(frame: &[u8]) -> Option<Extension> {
+        match decode_frame(frame).ok()?.as_slice() {
+            [YMessage::Custom(tag, data)] => Extension::from_custom(*tag, data).ok(),
+            _ => None,
+        }
+    }
 
-    let (state_bytes, state_vector_bytes) = {
-        let txn = doc.transact();
-        let state_bytes = txn.encode_state_as_update_v1(&StateVector::default());
-        let state_vector_bytes = txn.state_vector().encode_v1();
-        tracing::debug!(
-            "Encoded state: {} bytes, state_vector: {} bytes",
-            state_bytes.len(),
-            state_vector_bytes.len()
+    /// The update a frame carries, if it is a sync step 2
+    fn sync_step2(frame: &[u8]) -> Option<Vec<u8>> {
+        match decode_frame(frame).ok()?.as_slice() {
+            [YMessage::Sync(SyncMessage::SyncStep2(update))] => Some(update.clone()),
+            _ => None,
+        }
+    }
+
+    /// Connect and run the handshake, returning the server's state vector
+    async fn connect_with(url: &str, doc: &Doc) -> (Client, StateVector) {
+        let (mut client, _) = connect_async(url).await.expect("websocket should connect");
+        let step1 =
+            YMessage::Sync(SyncMessage::SyncStep1(doc.transact().state_vector())).encode_v1();
+        client
+            .send(tungstenite::Message::Binary(step1.into()))
+            .await
+            .unwrap();
+        // Sync step 2 and the document metadata, then the server's own sync step 1
+        for _ in 0..2 {
+            next_binary(&mut client).await.expect("handshake reply");
+        }
+        let server_step1 = next_binary(&mut client).await.expect("server sync step 1");
+        match decode_frame(&server_step1).unwrap().as_slice() {
+            [YMessage::Sync(SyncMessage::SyncStep1(state_vector))] => {
+                (client, state_vector.clone())
+            }
+            other => panic!("expected sync step 1, got {:?}", other),
+        }
+    }
+
+    /// Connect and run the handshake, then answer the server's sync step 1 the way
+    /// y-websocket does: with a sync step 2 that is empty when the client has nothing new
+    async fn connect(url: &str) -> Client {
+        let doc = Doc::new();
+        let (mut client, server_state) = connect_with(url, &doc).await;
+        let reply = YMessage::Sync(SyncMessage::SyncStep2(
+            doc.transact().encode_diff_v1(&server_state),
+        ))
+        .encode_v1();
+        client
+            .send(tungstenite::Message::Binary(reply.into()))
+            .await
+            .unwrap();
+        client
+    }
+
+    /// Running server with one vault owner
+    struct TestServer {
+        state: AppState,
+        /// Single-document endpoint for the guid passed to `setup`
+        url: String,
+        /// Multiplexed endpoint
+        mux_url: String,
+        vault_id: Uuid,
+        user_id: Uuid,
+    }
+
+    async fn setup(pool: PgPool, guid: &str) -> TestServer {
+        setup_with(pool, guid, SyncConfig::default()).await
+    }
+
+    async fn setup_with(pool: PgPool, guid: &str, sync: SyncConfig) -> TestServer {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let state = app_state(pool, sync);
+        let router = Router::new()
+            .route("/ws", get(ws_multiplex_handler))
+            .route("/ws/{doc}", get(ws_handler))
+            .with_state(state.clone());
+        let addr = serve(router).await;
+        let query = format!("token={}&vaultId={}", token_for(user_id), vault_id);
+        TestServer {
+            state,
+            url: format!("ws://{}/ws/{}?{}", addr, guid, query),
+            mux_url: format!("ws://{}/ws?{}", addr, query),
+            vault_id,
+            user_id,
+        }
+    }
+
+    /// Next binary frame, skipping control frames; `None` if nothing arrives in time
+    async fn next_binary(client: &mut Client) -> Option<Vec<u8>> {
+        timeout(Duration::from_millis(500), async {
+            loop {
+                match client.next().await? {
+                    Ok(tungstenite::Message::Binary(data)) => return Some(data.to_vec()),
+                    Ok(_) => continue,
+                    Err(_) => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[sqlx::test]
+    async fn updates_reach_peers_but_are_not_echoed(pool: PgPool) {
+        let server = setup(pool, "echo-doc").await;
+
+        let mut author = connect(&server.url).await;
+        let mut peer = connect(&server.url).await;
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        let update = {
+            let mut txn = doc.transact_mut();
+            text.push(&mut txn, "hello");
+            txn.encode_update_v1()
+        };
+        let message = YMessage::Sync(SyncMessage::Update(update.clone())).encode_v1();
+        author
+            .send(tungstenite::Message::Binary(message.clone().into()))
+            .await
+            .unwrap();
+
+        assert_eq!(next_binary(&mut peer).await, Some(message));
+        assert_eq!(next_binary(&mut author).await, None);
+    }
+
+    #[sqlx::test]
+    async fn offline_edits_are_merged_from_client_sync_step2(pool: PgPool) {
+        let server = setup(pool, "offline-doc").await;
+        let mut peer = connect(&server.url).await;
+
+        let offline = Doc::new();
+        let text = offline.get_or_insert_text("content");
+        text.push(&mut offline.transact_mut(), "written offline");
+
+        let (mut client, server_state_vector) = connect_with(&server.url, &offline).await;
+        let diff = offline.transact().encode_diff_v1(&server_state_vector);
+        client
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::SyncStep2(diff.clone()))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            next_binary(&mut peer).await,
+            Some(YMessage::Sync(SyncMessage::Update(diff.clone())).encode_v1())
         );
-        (state_bytes, state_vector_bytes)
-    };
 
-    // Insert or update document
-    tracing::debug!(
-        "Executing INSERT OR UPDATE query for guid: {} with vault_id: {}",
-        guid,
-        vault_id
-    );
-    let result = sqlx::query!(
-        r#"
-        INSERT INTO subdocs (guid, vault_id, doc_type, yjs_state, state_vector, modified_at)
-        VALUES ($1, $2, $3, $4, $5, NOW())
-        ON CONFLICT (guid) DO UPDATE
-        SET yjs_state = $4, state_vector = $5, modified_at = NOW()
-        "#,
-        guid,
-        vault_id,
-        doc_type,
-        state_bytes,
-        state_vector_bytes,
-    )
-    .execute(&state.pool)
-    .await?;
+        server
+            .state
+            .sync_manager
+            .flush("offline-doc")
+            .await
+            .unwrap();
+        let persisted: Vec<u8> =
+            sqlx::query_scalar("SELECT yjs_state FROM subdocs WHERE guid = 'offline-doc'")
+                .fetch_one(&server.state.pool)
+                .await
+                .unwrap();
+        let restored = Doc::new();
+        restored
+            .transact_mut()
+            .apply_update(Update::decode_v1(&persisted).unwrap())
+            .unwrap();
+        let content = restored.get_or_insert_text("content");
+        assert_eq!(content.get_string(&restored.transact()), "written offline");
+    }
 
-    // Ensure metadata record exists (insert default metadata if it doesn't)
-    sqlx::query!(
-        r#"
-        INSERT INTO subdoc_metadata (subdoc_guid, title, modified_at)
-        VALUES ($1, 'Untitled', NOW())
-        ON CONFLICT (subdoc_guid) DO UPDATE
-        SET modified_at = NOW()
-        "#,
-        guid
-    )
-    .execute(&state.pool)
-    .await?;
+    #[sqlx::test]
+    async fn lagged_subscribers_are_resynced(pool: PgPool) {
+        let sync = SyncConfig {
+            channel_capacity: 1,
+            ..SyncConfig::default()
+        };
+        let server = setup_with(pool, "lag-doc", sync).await;
+        let manager = &server.state.sync_manager;
+        let mut client = connect(&server.url).await;
 
-    tracing::info!(
-        "Saved document {} to vault {} by user {} (rows affected: {})",
-        guid,
-        vault_id,
-        user_id,
-        result.rows_affected()
-    );
-    Ok(())
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        let update = {
+            let mut txn = doc.transact_mut();
+            text.push(&mut txn, "missed");
+            txn.encode_update_v1()
+        };
+        manager
+            .apply_update("lag-doc", server.vault_id, server.user_id, &update)
+            .await
+            .unwrap();
+
+        // Flood the channel so the socket falls behind without ever seeing the update
+        for _ in 0..50 {
+            manager
+                .broadcast_update("lag-doc", None, vec![0xff])
+                .await
+                .unwrap();
+        }
+
+        let resync = loop {
+            let frame = next_binary(&mut client).await.expect("resync frame");
+            if let Some(update) = sync_step2(&frame) {
+                break update;
+            }
+        };
+        let resynced = Doc::new();
+        resynced
+            .transact_mut()
+            .apply_update(Update::decode_v1(&resync).unwrap())
+            .unwrap();
+        let content = resynced.get_or_insert_text("content");
+        assert_eq!(content.get_string(&resynced.transact()), "missed");
+    }
+
+    fn mux_frame(guid: &str, msg: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_var_string(&mut frame, guid);
+        frame.extend_from_slice(msg);
+        frame
+    }
+
+    fn text_update(content: &str) -> Vec<u8> {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("content");
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, content);
+        txn.encode_update_v1()
+    }
+
+    #[sqlx::test]
+    async fn one_connection_syncs_many_documents(pool: PgPool) {
+        let server = setup(pool, "doc-b").await;
+        let (mut mux, _) = connect_async(&server.mux_url).await.unwrap();
+
+        let step1 = YMessage::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        for guid in ["doc-a", "doc-b"] {
+            mux.send(tungstenite::Message::Binary(mux_frame(guid, &step1).into()))
+                .await
+                .unwrap();
+            // Sync step 2, metadata and the server's sync step 1, all addressed to `guid`
+            for _ in 0..3 {
+                let frame = next_binary(&mut mux).await.expect("handshake reply");
+                let (addressed_to, _) = read_var_string(&frame).unwrap();
+                assert_eq!(addressed_to, guid);
+            }
+        }
+
+        let mut author = connect(&server.url).await;
+        let first = YMessage::Sync(SyncMessage::Update(text_update("first"))).encode_v1();
+        author
+            .send(tungstenite::Message::Binary(first.clone().into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_binary(&mut mux).await,
+            Some(mux_frame("doc-b", &first))
+        );
+
+        // An empty body unsubscribes
+        mux.send(tungstenite::Message::Binary(mux_frame("doc-b", &[]).into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let second = YMessage::Sync(SyncMessage::Update(text_update("second"))).encode_v1();
+        author
+            .send(tungstenite::Message::Binary(second.into()))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut mux).await, None);
+    }
+
+    #[sqlx::test]
+    async fn the_empty_guid_takes_only_control_messages(pool: PgPool) {
+        let server = setup(pool, "unused-doc").await;
+        let (mut mux, _) = connect_async(&server.mux_url).await.unwrap();
+
+        let messages = [
+            YMessage::Sync(SyncMessage::SyncStep1(StateVector::default())),
+            YMessage::Sync(SyncMessage::Update(text_update("nameless"))),
+            Extension::MetadataPatch(r#"{"title":"Nameless"}"#.to_string()).to_message(),
+        ];
+        for message in messages {
+            mux.send(tungstenite::Message::Binary(
+                mux_frame("", &message.encode_v1()).into(),
+            ))
+            .await
+            .unwrap();
+            let frame = next_binary(&mut mux).await.expect("error frame");
+            let (addressed_to, body) = read_var_string(&frame).unwrap();
+            assert_eq!(addressed_to, "");
+            assert!(matches!(
+                extension(body),
+                Some(Extension::Error(ErrorFrame {
+                    code: ErrorCode::Malformed,
+                    ..
+                }))
+            ));
+        }
+        assert_eq!(next_binary(&mut mux).await, None);
+        let saved: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subdocs WHERE guid = ''")
+            .fetch_one(&server.state.pool)
+            .await
+            .unwrap();
+        assert_eq!(saved, 0);
+
+        // Connection-level control messages still go through
+        let auth = Extension::Control(ControlMessage::Auth {
+            token: token_for(server.user_id),
+        })
+        .encode();
+        mux.send(tungstenite::Message::Binary(mux_frame("", &auth).into()))
+            .await
+            .unwrap();
+        let frame = next_binary(&mut mux).await.expect("control frame");
+        let (addressed_to, body) = read_var_string(&frame).unwrap();
+        assert_eq!(addressed_to, "");
+        assert!(matches!(
+            extension(body),
+            Some(Extension::Control(ControlMessage::Authenticated { .. }))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn access_changes_downgrade_and_revoke_live_sessions(pool: PgPool) {
+        let server = setup(pool, "access-doc").await;
+        let pool = &server.state.pool;
+        let manager = &server.state.sync_manager;
+        let member_id = create_user(pool).await;
+        sqlx::query(
+            "INSERT INTO vault_members (vault_id, user_id, role) VALUES ($1, $2, 'editor')",
+        )
+        .bind(server.vault_id)
+        .bind(member_id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+        let (base, _) = server.url.split_once('?').unwrap();
+        let member_url = format!(
+            "{}?token={}&vaultId={}",
+            base,
+            token_for(member_id),
+            server.vault_id
+        );
+        let mut member = connect(&member_url).await;
+        let mut owner = connect(&server.url).await;
+
+        sqlx::query("UPDATE vault_members SET role = 'viewer' WHERE user_id = $1")
+            .bind(member_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        manager
+            .notify_access_change(AccessChange::vault(server.vault_id, Some(member_id)))
+            .await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Now a viewer, so the edit is dropped
+        member
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("not allowed")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(next_binary(&mut owner).await, None);
+
+        sqlx::query("DELETE FROM vault_members WHERE user_id = $1")
+            .bind(member_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        manager
+            .notify_access_change(AccessChange::vault(server.vault_id, Some(member_id)))
+            .await;
+
+        // A y-protocols permission-denied message comes right before the close
+        let mut denied = None;
+        let close = timeout(Duration::from_secs(1), async {
+            loop {
+                match member.next().await {
+                    Some(Ok(tungstenite::Message::Close(frame))) => return frame,
+                    Some(Ok(tungstenite::Message::Binary(frame))) => {
+                        if let Ok([YMessage::Auth(reason)]) = decode_frame(&frame).as_deref() {
+                            denied = reason.clone();
+                        }
+                    }
+                    Some(Ok(_)) => continue,
+                    _ => return None,
+                }
+            }
+        })
+        .await
+        .expect("socket should be closed");
+        assert_eq!(denied.as_deref(), Some("vault access revoked"));
+        assert_eq!(
+            close.map(|frame| u16::from(frame.code)),
+            Some(CLOSE_ACCESS_REVOKED)
+        );
+    }
+
+    #[sqlx::test]
+    async fn batched_frames_and_awareness_queries_are_answered(pool: PgPool) {
+        let server = setup(pool, "query-doc").await;
+        let mut client = connect(&server.url).await;
+
+        let state = AwarenessUpdate {
+            clients: HashMap::from([(
+                7,
+                yrs::sync::awareness::AwarenessUpdateEntry {
+                    clock: 1,
+                    json: r#"{"user":"ada"}"#.into(),
+                },
+            )]),
+        };
+        // Announce a state and ask for everyone's in one frame
+        let mut frame = YMessage::Awareness(state.clone()).encode_v1();
+        frame.extend(YMessage::AwarenessQuery.encode_v1());
+        client
+            .send(tungstenite::Message::Binary(frame.into()))
+            .await
+            .unwrap();
+
+        let reply = next_binary(&mut client).await.expect("awareness reply");
+        assert_eq!(decode_frame(&reply), Ok(vec![YMessage::Awareness(state)]));
+    }
+
+    /// Access token for `user_id` that expires `secs` seconds from now
+    fn token_expiring_in(user_id: Uuid, secs: i64) -> String {
+        let claims = Claims {
+            sub: user_id,
+            exp: (chrono::Utc::now().timestamp() + secs) as usize,
+        };
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(crate::test_support::JWT_SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// Next control message, skipping document frames
+    async fn next_control(client: &mut Client) -> Option<ControlMessage> {
+        loop {
+            let frame = next_binary(client).await?;
+            if let Some(Extension::Control(control)) = extension(&frame) {
+                return Some(control);
+            }
+        }
+    }
+
+    async fn send_control(client: &mut Client, control: &ControlMessage) {
+        client
+            .send(tungstenite::Message::Binary(
+                Extension::Control(control.clone()).encode().into(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn sockets_without_a_token_authenticate_in_band(pool: PgPool) {
+        let server = setup(pool, "in-band-doc").await;
+        let (base, _) = server.url.split_once('?').unwrap();
+        let url = format!("{}?vaultId={}", base, server.vault_id);
+
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        send_control(
+            &mut client,
+            &ControlMessage::Auth {
+                token: token_for(server.user_id),
+            },
+        )
+        .await;
+        assert!(matches!(
+            next_control(&mut client).await,
+            Some(ControlMessage::Authenticated { .. })
+        ));
+
+        // A socket that sends anything else first is turned away
+        let (mut rejected, _) = connect_async(&url).await.unwrap();
+        rejected
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::SyncStep1(StateVector::default()))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        let close = loop {
+            match rejected.next().await {
+                Some(Ok(tungstenite::Message::Close(frame))) => break frame,
+                Some(Ok(_)) => continue,
+                _ => break None,
+            }
+        };
+        assert_eq!(
+            close.map(|frame| u16::from(frame.code)),
+            Some(CLOSE_UNAUTHORIZED)
+        );
+    }
+
+    #[sqlx::test]
+    async fn expiring_tokens_are_renewed_in_band_or_close_the_session(pool: PgPool) {
+        let server = setup(pool, "expiry-doc").await;
+        let (base, _) = server.url.split_once('?').unwrap();
+        let url_with =
+            |token: String| format!("{}?token={}&vaultId={}", base, token, server.vault_id);
+
+        // Within the warning window from the start, so the warning comes right away
+        let (mut renewing, _) = connect_async(url_with(token_expiring_in(server.user_id, 2)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_control(&mut renewing).await,
+            Some(ControlMessage::TokenExpiring { .. })
+        ));
+        send_control(
+            &mut renewing,
+            &ControlMessage::Auth {
+                token: token_for(server.user_id),
+            },
+        )
+        .await;
+        assert!(matches!(
+            next_control(&mut renewing).await,
+            Some(ControlMessage::Authenticated { .. })
+        ));
+
+        let (mut lapsing, _) = connect_async(url_with(token_expiring_in(server.user_id, 1)))
+            .await
+            .unwrap();
+        let close = timeout(Duration::from_secs(3), async {
+            loop {
+                match lapsing.next().await {
+                    Some(Ok(tungstenite::Message::Close(frame))) => return frame,
+                    Some(Ok(_)) => continue,
+                    _ => return None,
+                }
+            }
+        })
+        .await
+        .expect("socket should be closed");
+        assert_eq!(
+            close.map(|frame| u16::from(frame.code)),
+            Some(CLOSE_UNAUTHORIZED)
+        );
+
+        // The renewed session outlives the original token and keeps syncing
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        renewing
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::SyncStep1(StateVector::default()))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        assert!(next_binary(&mut renewing).await.is_some());
+    }
+
+    #[sqlx::test]
+    async fn refused_messages_get_an_error_frame_and_the_server_state(pool: PgPool) {
+        let server = setup(pool, "refused-doc").await;
+        let viewer_id = create_user(&server.state.pool).await;
+        sqlx::query(
+            "INSERT INTO vault_members (vault_id, user_id, role) VALUES ($1, $2, 'viewer')",
+        )
+        .bind(server.vault_id)
+        .bind(viewer_id)
+        .execute(&server.state.pool)
+        .await
+        .unwrap();
+        let (base, _) = server.url.split_once('?').unwrap();
+        let viewer_url = format!(
+            "{}?token={}&vaultId={}",
+            base,
+            token_for(viewer_id),
+            server.vault_id
+        );
+
+        let mut owner = connect(&server.url).await;
+        owner
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("server text")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+
+        let mut viewer = connect(&viewer_url).await;
+        viewer
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("local edit")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+
+        // The owner's update may be relayed first
+        let error = loop {
+            let frame = next_binary(&mut viewer).await.expect("error frame");
+            if let Some(Extension::Error(error)) = extension(&frame) {
+                break error;
+            }
+        };
+        assert_eq!(error.code, ErrorCode::ReadOnly);
+        let frame = next_binary(&mut viewer).await.expect("server state");
+        let Some(state) = sync_step2(&frame) else {
+            panic!("expected sync step 2");
+        };
+        let restored = Doc::new();
+        restored
+            .transact_mut()
+            .apply_update(Update::decode_v1(&state).unwrap())
+            .unwrap();
+        let content = restored.get_or_insert_text("content");
+        assert_eq!(content.get_string(&restored.transact()), "server text");
+
+        // Unknown message types are reported instead of silently dropped
+        owner
+            .send(tungstenite::Message::Binary(vec![0x09, 0x00].into()))
+            .await
+            .unwrap();
+        let frame = next_binary(&mut owner).await.expect("error frame");
+        assert!(matches!(
+            extension(&frame),
+            Some(Extension::Error(ErrorFrame {
+                code: ErrorCode::Malformed,
+                ..
+            }))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn empty_handshake_replies_are_not_refused(pool: PgPool) {
+        let server = setup(pool, "handshake-doc").await;
+        let viewer_id = create_user(&server.state.pool).await;
+        sqlx::query(
+            "INSERT INTO vault_members (vault_id, user_id, role) VALUES ($1, $2, 'viewer')",
+        )
+        .bind(server.vault_id)
+        .bind(viewer_id)
+        .execute(&server.state.pool)
+        .await
+        .unwrap();
+        let (base, _) = server.url.split_once('?').unwrap();
+        let viewer_url = format!(
+            "{}?token={}&vaultId={}",
+            base,
+            token_for(viewer_id),
+            server.vault_id
+        );
+
+        let mut owner = connect(&server.url).await;
+        owner
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("server text")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Neither an error frame nor a second copy of the document
+        let mut viewer = connect(&viewer_url).await;
+        assert_eq!(next_binary(&mut viewer).await, None);
+
+        sqlx::query("UPDATE subdocs SET deleted_at = NOW() WHERE guid = 'handshake-doc'")
+            .execute(&server.state.pool)
+            .await
+            .unwrap();
+        server
+            .state
+            .sync_manager
+            .notify_trash_change(
+                &["handshake-doc".to_string()],
+                TrashChange {
+                    vault_id: server.vault_id,
+                    deleted: true,
+                },
+            )
+            .await;
+        assert_eq!(
+            next_control(&mut owner).await,
+            Some(ControlMessage::DocDeleted)
+        );
+
+        let mut reader = connect(&server.url).await;
+        assert_eq!(
+            next_control(&mut reader).await,
+            Some(ControlMessage::DocDeleted)
+        );
+        assert_eq!(next_binary(&mut reader).await, None);
+    }
+
+    /// Try to read, edit and rename a document, expecting `doc_not_found` every time
+    async fn assert_every_access_refused(client: &mut Client) {
+        let messages = [
+            YMessage::Sync(SyncMessage::SyncStep1(StateVector::default())),
+            YMessage::Sync(SyncMessage::Update(text_update("overwritten"))),
+            Extension::MetadataPatch(r#"{"title":"Mine now"}"#.to_string()).to_message(),
+        ];
+        for message in messages {
+            client
+                .send(tungstenite::Message::Binary(message.encode_v1().into()))
+                .await
+                .unwrap();
+            let frame = next_binary(client).await.expect("error frame");
+            assert!(matches!(
+                extension(&frame),
+                Some(Extension::Error(ErrorFrame {
+                    code: ErrorCode::DocNotFound,
+                    ..
+                }))
+            ));
+        }
+        // Neither the document's state nor its metadata leaks
+        assert_eq!(next_binary(client).await, None);
+    }
+
+    #[sqlx::test]
+    async fn documents_of_other_vaults_can_be_neither_read_nor_written(pool: PgPool) {
+        let server = setup(pool, "foreign-doc").await;
+        let pool = &server.state.pool;
+
+        // Another user's vault already has a document under the same guid
+        let stranger = create_user(pool).await;
+        let foreign_vault = create_vault(pool, stranger).await;
+        let foreign_doc = Doc::new();
+        foreign_doc
+            .transact_mut()
+            .apply_update(Update::decode_v1(&text_update("secret")).unwrap())
+            .unwrap();
+        let (state, state_vector) = {
+            let txn = foreign_doc.transact();
+            (
+                txn.encode_state_as_update_v1(&StateVector::default()),
+                txn.state_vector().encode_v1(),
+            )
+        };
+        sqlx::query(
+            "INSERT INTO subdocs (guid, vault_id, doc_type, yjs_state, state_vector)
+             VALUES ('foreign-doc', $1, 'document', $2, $3)",
+        )
+        .bind(foreign_vault)
+        .bind(&state)
+        .bind(&state_vector)
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO subdoc_metadata (subdoc_guid, title) VALUES ('foreign-doc', 'Secret')",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        // Only in the database
+        let (mut client, _) = connect_async(&server.url).await.unwrap();
+        assert_every_access_refused(&mut client).await;
+
+        // Held live by a session of its own vault
+        let (base, _) = server.url.split_once('?').unwrap();
+        let foreign_url = format!(
+            "{}?token={}&vaultId={}",
+            base,
+            token_for(stranger),
+            foreign_vault
+        );
+        let mut foreign = connect(&foreign_url).await;
+        assert_every_access_refused(&mut client).await;
+        assert_eq!(next_binary(&mut foreign).await, None);
+
+        server
+            .state
+            .sync_manager
+            .flush("foreign-doc")
+            .await
+            .unwrap();
+        let (persisted, logged): (Vec<u8>, i64) = sqlx::query_as(
+            "SELECT yjs_state, (SELECT COUNT(*) FROM document_updates WHERE subdoc_guid = guid)
+             FROM subdocs WHERE guid = 'foreign-doc' AND vault_id = $1",
+        )
+        .bind(foreign_vault)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(persisted, state);
+        assert_eq!(logged, 0);
+    }
+
+    #[sqlx::test]
+    async fn unknown_guids_follow_the_vault_creation_policy(pool: PgPool) {
+        let server = setup(pool, "parent-doc").await;
+        let pool = &server.state.pool;
+        let (base, query) = server.url.split_once("/parent-doc?").unwrap();
+        let url_for = |guid: &str| format!("{}/{}?{}", base, guid, query);
+
+        // The default policy creates documents on the first edit, as before
+        let mut parent = connect(&server.url).await;
+        parent
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("parent")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        sqlx::query("UPDATE vaults SET doc_creation_policy = 'reject' WHERE id = $1")
+            .bind(server.vault_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        let (mut client, _) = connect_async(url_for("typo-doc")).await.unwrap();
+        for message in [
+            YMessage::Sync(SyncMessage::SyncStep1(StateVector::default())),
+            YMessage::Sync(SyncMessage::Update(text_update("junk"))),
+        ] {
+            client
+                .send(tungstenite::Message::Binary(message.encode_v1().into()))
+                .await
+                .unwrap();
+            assert!(matches!(
+                next_error(&mut client).await,
+                Some(ErrorFrame {
+                    code: ErrorCode::DocNotFound,
+                    ..
+                })
+            ));
+        }
+
+        sqlx::query(
+            "UPDATE vaults SET doc_creation_policy = 'auto_create',
+                auto_create_doc_type = 'canvas', auto_create_parent_guid = 'parent-doc'
+             WHERE id = $1",
+        )
+        .bind(server.vault_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        let mut client = connect(&url_for("child-doc")).await;
+        client
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("child")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let guids: Vec<String> =
+            sqlx::query_scalar("SELECT guid FROM subdocs WHERE vault_id = $1 ORDER BY guid")
+                .bind(server.vault_id)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        assert_eq!(guids, vec!["child-doc", "parent-doc"]);
+        let (doc_type, parent_guid): (String, Option<String>) =
+            sqlx::query_as("SELECT doc_type, parent_guid FROM subdocs WHERE guid = 'child-doc'")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert_eq!(doc_type, "canvas");
+        assert_eq!(parent_guid.as_deref(), Some("parent-doc"));
+
+        // Both creations are in the audit log, attributed to the session that made them
+        let created: Vec<(String, Uuid, Option<Uuid>)> = sqlx::query_as(
+            "SELECT subdoc_guid, user_id, session_id FROM document_edits
+             WHERE edit_type = 'create' ORDER BY subdoc_guid",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0].0, "child-doc");
+        assert!(
+            created
+                .iter()
+                .all(|(_, user_id, session_id)| *user_id == server.user_id && session_id.is_some())
+        );
+    }
+
+    #[sqlx::test]
+    async fn trashed_documents_refuse_updates_unless_owners_restore_on_edit(pool: PgPool) {
+        let server = setup(pool, "trash-doc").await;
+        let pool = &server.state.pool;
+        let member_id = create_user(pool).await;
+        sqlx::query(
+            "INSERT INTO vault_members (vault_id, user_id, role) VALUES ($1, $2, 'editor')",
+        )
+        .bind(server.vault_id)
+        .bind(member_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        let (base, _) = server.url.split_once('?').unwrap();
+        let member_url = format!(
+            "{}?token={}&vaultId={}",
+            base,
+            token_for(member_id),
+            server.vault_id
+        );
+        let update = |content: &str| {
+            tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update(content)))
+                    .encode_v1()
+                    .into(),
+            )
+        };
+
+        let mut owner = connect(&server.url).await;
+        owner.send(update("draft")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut member = connect(&member_url).await;
+
+        sqlx::query("UPDATE subdocs SET deleted_at = NOW() WHERE guid = 'trash-doc'")
+            .execute(pool)
+            .await
+            .unwrap();
+        server
+            .state
+            .sync_manager
+            .notify_trash_change(
+                &["trash-doc".to_string()],
+                TrashChange {
+                    vault_id: server.vault_id,
+                    deleted: true,
+                },
+            )
+            .await;
+        for client in [&mut owner, &mut member] {
+            assert_eq!(next_control(client).await, Some(ControlMessage::DocDeleted));
+        }
+
+        // Clients can still read the document, but are told it is in the trash
+        let mut reader = connect(&member_url).await;
+        assert_eq!(
+            next_control(&mut reader).await,
+            Some(ControlMessage::DocDeleted)
+        );
+
+        // Without restore on edit nobody may edit it, owners included
+        for client in [&mut member, &mut owner] {
+            client.send(update("junk")).await.unwrap();
+            assert!(matches!(
+                next_error(client).await,
+                Some(ErrorFrame {
+                    code: ErrorCode::DocDeleted,
+                    ..
+                })
+            ));
+        }
+        server.state.sync_manager.flush("trash-doc").await.unwrap();
+        let logged: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM document_updates WHERE subdoc_guid = 'trash-doc'",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(logged, 0);
+
+        // With it, an owner's edit takes the document out of the trash
+        sqlx::query("UPDATE vaults SET restore_on_edit = TRUE WHERE id = $1")
+            .bind(server.vault_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        member.send(update("junk")).await.unwrap();
+        assert!(matches!(
+            next_error(&mut member).await,
+            Some(ErrorFrame {
+                code: ErrorCode::DocDeleted,
+                ..
+            })
+        ));
+        owner.send(update("restored")).await.unwrap();
+        assert_eq!(
+            next_control(&mut member).await,
+            Some(ControlMessage::DocRestored)
+        );
+        let deleted: bool = sqlx::query_scalar(
+            "SELECT deleted_at IS NOT NULL FROM subdocs WHERE guid = 'trash-doc'",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert!(!deleted);
+
+        member.send(update("edit")).await.unwrap();
+        assert_eq!(next_error(&mut member).await, None);
+    }
+
+    #[sqlx::test]
+    async fn metadata_patches_reach_subscribers_and_vault_listeners(pool: PgPool) {
+        let server = setup(pool, "meta-doc").await;
+        let (mut listener, _) = connect_async(&server.mux_url).await.unwrap();
+
+        let mut editor = connect(&server.url).await;
+        // Saves the new document, so there is a metadata row to patch
+        editor
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("body")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let patch = r#"{"title":"Renamed","icon":"📄","tags":["a"]}"#;
+        editor
+            .send(tungstenite::Message::Binary(
+                Extension::MetadataPatch(patch.to_string()).encode().into(),
+            ))
+            .await
+            .unwrap();
+
+        let metadata = |frame: Vec<u8>| match extension(&frame) {
+            Some(Extension::Metadata(json)) => {
+                serde_json::from_str::<crate::models::DocumentMetadata>(&json).unwrap()
+            }
+            other => panic!("expected metadata, got {:?}", other),
+        };
+        let confirmed = metadata(next_binary(&mut editor).await.expect("metadata"));
+        assert_eq!(confirmed.title, "Renamed");
+        assert_eq!(confirmed.icon.as_deref(), Some("📄"));
+        assert_eq!(confirmed.tags, vec!["a".to_string()]);
+
+        let frame = next_binary(&mut listener).await.expect("vault metadata");
+        let (guid, body) = read_var_string(&frame).unwrap();
+        assert_eq!(guid, "meta-doc");
+        assert_eq!(metadata(body.to_vec()).title, "Renamed");
+
+        // null clears, absent fields stay
+        editor
+            .send(tungstenite::Message::Binary(
+                Extension::MetadataPatch(r#"{"icon":null}"#.to_string())
+                    .encode()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        let cleared = metadata(next_binary(&mut editor).await.expect("metadata"));
+        assert_eq!(cleared.icon, None);
+        assert_eq!(cleared.title, "Renamed");
+
+        let title: String =
+            sqlx::query_scalar("SELECT title FROM subdoc_metadata WHERE subdoc_guid = 'meta-doc'")
+                .fetch_one(&server.state.pool)
+                .await
+                .unwrap();
+        assert_eq!(title, "Renamed");
+    }
+
+    /// Next error frame, skipping anything relayed or resynced in between
+    async fn next_error(client: &mut Client) -> Option<ErrorFrame> {
+        loop {
+            let frame = next_binary(client).await?;
+            if let Some(Extension::Error(error)) = extension(&frame) {
+                return Some(error);
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn limits_refuse_messages_and_disconnect_repeat_offenders(pool: PgPool) {
+        let sync = SyncConfig {
+            max_frame_bytes: 256,
+            max_updates_per_sec: 2,
+            max_document_bytes: 128,
+            max_violations: 3,
+            ..SyncConfig::default()
+        };
+        let server = setup_with(pool, "limits-doc", sync).await;
+        let mut client = connect(&server.url).await;
+        let send_update = |content: &str| {
+            tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update(content)))
+                    .encode_v1()
+                    .into(),
+            )
+        };
+
+        client.send(send_update(&"x".repeat(300))).await.unwrap();
+        let error = next_error(&mut client).await.expect("frame size error");
+        assert_eq!(error.code, ErrorCode::TooLarge);
+
+        // A burst of one second's worth goes through, the next update does not
+        for content in ["a", "b", "c"] {
+            client.send(send_update(content)).await.unwrap();
+        }
+        let error = next_error(&mut client).await.expect("rate limit error");
+        assert_eq!(error.code, ErrorCode::RateLimited);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        client.send(send_update(&"y".repeat(200))).await.unwrap();
+        let error = next_error(&mut client).await.expect("document size error");
+        assert_eq!(error.code, ErrorCode::TooLarge);
+
+        // That was the third violation
+        let close = timeout(Duration::from_secs(1), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(tungstenite::Message::Close(frame))) => return frame,
+                    Some(Ok(_)) => continue,
+                    _ => return None,
+                }
+            }
+        })
+        .await
+        .expect("socket should be closed");
+        assert_eq!(
+            close.map(|frame| u16::from(frame.code)),
+            Some(CLOSE_POLICY_VIOLATION)
+        );
+
+        let metrics = server.state.sync_manager.metrics().render(&[]);
+        for line in [
+            "sync_updates_applied_total 2",
+            "sync_limit_violations_total{limit=\"frame_size\"} 1",
+            "sync_limit_violations_total{limit=\"update_rate\"} 1",
+            "sync_limit_violations_total{limit=\"document_size\"} 1",
+            "sync_sessions_disconnected_total 1",
+        ] {
+            assert!(metrics.contains(line), "missing {:?} in\n{}", line, metrics);
+        }
+    }
+
+    #[sqlx::test]
+    async fn messages_far_over_the_frame_limit_close_the_socket(pool: PgPool) {
+        let sync = SyncConfig {
+            max_frame_bytes: 256,
+            ..SyncConfig::default()
+        };
+        let server = setup_with(pool, "huge-doc", sync).await;
+        let mut client = connect(&server.url).await;
+
+        client
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update(&"x".repeat(4096))))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        let closed = timeout(Duration::from_secs(1), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok(), "socket should be closed");
+        server.state.sync_manager.flush("huge-doc").await.unwrap();
+        let saved: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subdocs WHERE guid = 'huge-doc'")
+            .fetch_one(&server.state.pool)
+            .await
+            .unwrap();
+        assert_eq!(saved, 0);
+    }
+
+    #[sqlx::test]
+    async fn shutdown_closes_sessions_and_flushes_documents(pool: PgPool) {
+        let server = setup(pool, "shutdown-doc").await;
+        let mut client = connect(&server.url).await;
+        client
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("saved ")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        client
+            .send(tungstenite::Message::Binary(
+                YMessage::Sync(SyncMessage::Update(text_update("on shutdown")))
+                    .encode_v1()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let manager = server.state.sync_manager.clone();
+        let shutdown = tokio::spawn(async move { manager.shutdown(Duration::from_secs(5)).await });
+
+        let close = timeout(Duration::from_secs(1), async {
+            loop {
+                match client.next().await {
+                    Some(Ok(tungstenite::Message::Close(frame))) => return frame,
+                    Some(Ok(_)) => continue,
+                    _ => return None,
+                }
+            }
+        })
+        .await
+        .expect("socket should be closed");
+        assert_eq!(
+            close.map(|frame| u16::from(frame.code)),
+            Some(CLOSE_SERVICE_RESTART)
+        );
+
+        match connect_async(&server.url).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!(
+                "expected the upgrade to be refused, got {:?}",
+                other.is_ok()
+            ),
+        }
+
+        timeout(Duration::from_secs(5), shutdown)
+            .await
+            .expect("shutdown should finish")
+            .unwrap();
+        // The first update created the row, the second was only in memory until now
+        let logged: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM document_updates WHERE subdoc_guid = 'shutdown-doc'",
+        )
+        .fetch_one(&server.state.pool)
+        .await
+        .unwrap();
+        assert_eq!(logged, 1);
+    }
+
+    #[sqlx::test]
+    async fn sessions_are_tracked_per_vault_and_document(pool: PgPool) {
+        let server = setup(pool, "presence-doc").await;
+        let manager = &server.state.sync_manager;
+        let (mut idle, _) = connect_async(&server.mux_url).await.unwrap();
+        let editor = connect(&server.url).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let presence = manager.vault_presence(server.vault_id);
+        assert_eq!(
+            presence.user_ids.into_iter().collect::<Vec<_>>(),
+            vec![server.user_id]
+        );
+        assert_eq!(
+            presence.documents.keys().collect::<Vec<_>>(),
+            vec!["presence-doc"]
+        );
+
+        drop(editor);
+        idle.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            manager.vault_presence(server.vault_id),
+            crate::sync::VaultPresence::default()
+        );
+    }
 }
@@ -1,23 +1,42 @@
 use chrono::Utc;
 use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio::time::{Duration, interval};
 
-pub async fn start_cleanup_job(pool: PgPool) {
+use crate::api::uploads::Upload;
+use crate::storage::{BlobStorage, StorageError};
+
+/// Days a soft-deleted vault, document or upload is kept before it is purged
+pub const TRASH_RETENTION_DAYS: i64 = 30;
+
+/// Run the cleanup every hour until `shutdown` turns `true`. A run that is in
+/// progress when shutdown starts is finished first.
+pub fn start_cleanup_job(
+    pool: PgPool,
+    storage: Arc<dyn BlobStorage>,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut tick = interval(Duration::from_secs(3600)); // Run every hour
 
         loop {
-            tick.tick().await;
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = shutdown.wait_for(|stopping| *stopping) => break,
+            }
 
-            if let Err(e) = cleanup_old_deletions(&pool).await {
+            if let Err(e) = cleanup_old_deletions(&pool, storage.as_ref()).await {
                 tracing::error!("Cleanup job failed: {}", e);
             }
         }
-    });
+        tracing::debug!("Cleanup job stopped");
+    })
 }
 
-async fn cleanup_old_deletions(pool: &PgPool) -> anyhow::Result<()> {
-    let cutoff = Utc::now() - chrono::Duration::days(30);
+async fn cleanup_old_deletions(pool: &PgPool, storage: &dyn BlobStorage) -> anyhow::Result<()> {
+    let cutoff = Utc::now() - chrono::Duration::days(TRASH_RETENTION_DAYS);
 
     // Permanently delete vaults (cascades to subdocs, metadata, etc.)
     let result = sqlx::query("DELETE FROM vaults WHERE deleted_at IS NOT NULL AND deleted_at < $1")
@@ -29,5 +48,235 @@ async fn cleanup_old_deletions(pool: &PgPool) -> anyhow::Result<()> {
         tracing::info!("Permanently deleted {} vaults", result.rows_affected());
     }
 
+    // Documents deleted on their own (cascades to metadata, update log and audit log)
+    let purged: Vec<String> = sqlx::query_scalar(
+        "DELETE FROM subdocs WHERE deleted_at IS NOT NULL AND deleted_at < $1 RETURNING guid",
+    )
+    .bind(cutoff)
+    .fetch_all(pool)
+    .await?;
+
+    if !purged.is_empty() {
+        // Documents restored on their own would otherwise point at a parent that is gone
+        sqlx::query("UPDATE subdocs SET parent_guid = NULL WHERE parent_guid = ANY($1)")
+            .bind(&purged)
+            .execute(pool)
+            .await?;
+        tracing::info!("Permanently deleted {} documents", purged.len());
+    }
+
+    // Uploads go once their file is gone from storage
+    let uploads = sqlx::query_as::<_, Upload>(
+        "SELECT id, user_id, filename, original_filename, mime_type, size_bytes, storage_key, created_at, deleted_at, deleted_by
+         FROM uploads WHERE deleted_at IS NOT NULL AND deleted_at < $1",
+    )
+    .bind(cutoff)
+    .fetch_all(pool)
+    .await?;
+
+    let mut purged_uploads = 0;
+    for upload in &uploads {
+        match storage.delete(&upload.storage_key).await {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => {
+                tracing::error!(
+                    "Failed to delete file {} of upload {}: {}",
+                    upload.storage_key,
+                    upload.id,
+                    e
+                );
+                continue;
+            }
+        }
+        sqlx::query("DELETE FROM uploads WHERE id = $1")
+            .bind(upload.id)
+            .execute(pool)
+            .await?;
+        purged_uploads += 1;
+    }
+
+    if purged_uploads > 0 {
+        tracing::info!("Permanently deleted {} uploads", purged_uploads);
+    }
+
+    // Oversized sync events are only needed until every instance has read them
+    let result =
+        sqlx::query("DELETE FROM sync_event_payloads WHERE created_at < NOW() - INTERVAL '1 hour'")
+            .execute(pool)
+            .await?;
+
+    if result.rows_affected() > 0 {
+        tracing::debug!(
+            "Deleted {} stale sync event payloads",
+            result.rows_affected()
+        );
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::UploadedFile;
+    use crate::test_support::{create_user, create_vault};
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    /// Storage that fails or misses the keys it is told to, and records for every delete
+    /// whether the upload's row was still there
+    struct StubStorage {
+        pool: PgPool,
+        missing: &'static str,
+        failing: &'static str,
+        deletes: Mutex<Vec<(String, bool)>>,
+    }
+
+    #[async_trait]
+    impl BlobStorage for StubStorage {
+        async fn store(&self, _data: &[u8], _filename: &str) -> Result<UploadedFile, StorageError> {
+            // Cleanup never stores files
+            Err(StorageError::IoError(
+                "stub storage is read-only".to_string(),
+            ))
+        }
+
+        async fn retrieve(&self, _storage_key: &str) -> Result<Vec<u8>, StorageError> {
+            // Cleanup never reads files
+            Err(StorageError::NotFound)
+        }
+
+        async fn delete(&self, storage_key: &str) -> Result<(), StorageError> {
+            let row_exists: bool =
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM uploads WHERE storage_key = $1)")
+                    .bind(storage_key)
+                    .fetch_one(&self.pool)
+                    .await
+                    .unwrap();
+            self.deletes
+                .lock()
+                .unwrap()
+                .push((storage_key.to_string(), row_exists));
+
+            if storage_key == self.missing {
+                Err(StorageError::NotFound)
+            } else if storage_key == self.failing {
+                Err(StorageError::IoError("disk on fire".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn get_url(&self, storage_key: &str) -> String {
+            format!("/uploads/{}", storage_key)
+        }
+    }
+
+    impl StubStorage {
+        fn new(pool: PgPool) -> Self {
+            Self {
+                pool,
+                missing: "missing",
+                failing: "failing",
+                deletes: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    async fn insert_document(
+        pool: &PgPool,
+        vault_id: Uuid,
+        guid: &str,
+        parent_guid: Option<&str>,
+        deleted_days_ago: Option<i64>,
+    ) {
+        sqlx::query(
+            "INSERT INTO subdocs (guid, vault_id, parent_guid, doc_type, yjs_state, state_vector, deleted_at)
+             VALUES ($1, $2, $3, 'document', '', '', NOW() - make_interval(days => $4::int))",
+        )
+        .bind(guid)
+        .bind(vault_id)
+        .bind(parent_guid)
+        .bind(deleted_days_ago.map(|days| days as i32))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn insert_upload(pool: &PgPool, user_id: Uuid, storage_key: &str, deleted_days_ago: i64) {
+        sqlx::query(
+            "INSERT INTO uploads (user_id, filename, original_filename, mime_type, size_bytes, storage_key, deleted_at)
+             VALUES ($1, $2, $2, 'text/plain', 1, $2, NOW() - make_interval(days => $3::int))",
+        )
+        .bind(user_id)
+        .bind(storage_key)
+        .bind(deleted_days_ago as i32)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn parent_of(pool: &PgPool, guid: &str) -> Option<Option<String>> {
+        sqlx::query_scalar("SELECT parent_guid FROM subdocs WHERE guid = $1")
+            .bind(guid)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn documents_past_retention_are_purged_and_detached_from_their_children(pool: PgPool) {
+        let owner = create_user(&pool).await;
+        let vault_id = create_vault(&pool, owner).await;
+        let expired = TRASH_RETENTION_DAYS + 1;
+        insert_document(&pool, vault_id, "old", None, Some(expired)).await;
+        // Restored on its own after its parent was deleted
+        insert_document(&pool, vault_id, "old-child", Some("old"), None).await;
+        insert_document(&pool, vault_id, "recent", None, Some(10)).await;
+        insert_document(&pool, vault_id, "recent-child", Some("recent"), Some(10)).await;
+
+        let storage = StubStorage::new(pool.clone());
+        cleanup_old_deletions(&pool, &storage).await.unwrap();
+
+        assert_eq!(parent_of(&pool, "old").await, None);
+        assert_eq!(parent_of(&pool, "old-child").await, Some(None));
+        assert_eq!(parent_of(&pool, "recent").await, Some(None));
+        assert_eq!(
+            parent_of(&pool, "recent-child").await,
+            Some(Some("recent".to_string()))
+        );
+    }
+
+    #[sqlx::test]
+    async fn uploads_are_purged_only_once_their_file_is_gone(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let expired = TRASH_RETENTION_DAYS + 1;
+        insert_upload(&pool, user_id, "deleted", expired).await;
+        insert_upload(&pool, user_id, "missing", expired).await;
+        insert_upload(&pool, user_id, "failing", expired).await;
+        insert_upload(&pool, user_id, "recent", 10).await;
+
+        let storage = StubStorage::new(pool.clone());
+        cleanup_old_deletions(&pool, &storage).await.unwrap();
+
+        // Every file went before its row did, and recent uploads were left alone
+        let mut deletes = storage.deletes.lock().unwrap().clone();
+        deletes.sort();
+        assert_eq!(
+            deletes,
+            vec![
+                ("deleted".to_string(), true),
+                ("failing".to_string(), true),
+                ("missing".to_string(), true),
+            ]
+        );
+
+        let mut remaining: Vec<String> = sqlx::query_scalar("SELECT storage_key FROM uploads")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        remaining.sort();
+        assert_eq!(remaining, vec!["failing".to_string(), "recent".to_string()]);
+    }
+}
@@ -1,11 +1,16 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
+use crate::sync::SyncConfig;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub database_url: String,
     pub port: u16,
     pub jwt_secret: String,
+    /// Bearer token for `/metrics`; the endpoint is off without one
+    pub metrics_token: Option<String>,
+    pub sync: SyncConfig,
 }
 
 impl Config {
@@ -21,10 +26,60 @@ impl Config {
 
         let jwt_secret = std::env::var("JWT_SECRET").context("JWT_SECRET must be set")?;
 
+        let metrics_token = std::env::var("METRICS_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
+        let defaults = SyncConfig::default();
+        let sync = SyncConfig {
+            flush_debounce_ms: env_or("SYNC_FLUSH_DEBOUNCE_MS", defaults.flush_debounce_ms)?,
+            flush_max_delay_ms: env_or("SYNC_FLUSH_MAX_DELAY_MS", defaults.flush_max_delay_ms)?,
+            channel_capacity: env_or("SYNC_CHANNEL_CAPACITY", defaults.channel_capacity)?,
+            backend: env_or("SYNC_BACKEND", defaults.backend)?,
+            compact_max_updates: env_or("SYNC_COMPACT_MAX_UPDATES", defaults.compact_max_updates)?,
+            compact_max_bytes: env_or("SYNC_COMPACT_MAX_BYTES", defaults.compact_max_bytes)?,
+            compact_interval_secs: env_or(
+                "SYNC_COMPACT_INTERVAL_SECS",
+                defaults.compact_interval_secs,
+            )?,
+            max_frame_bytes: env_or("SYNC_MAX_FRAME_BYTES", defaults.max_frame_bytes)?,
+            max_updates_per_sec: env_or("SYNC_MAX_UPDATES_PER_SEC", defaults.max_updates_per_sec)?,
+            max_document_bytes: env_or("SYNC_MAX_DOCUMENT_BYTES", defaults.max_document_bytes)?,
+            max_violations: env_or("SYNC_MAX_VIOLATIONS", defaults.max_violations)?,
+            violation_window_secs: env_or(
+                "SYNC_VIOLATION_WINDOW_SECS",
+                defaults.violation_window_secs,
+            )?,
+            idle_eviction_ms: env_or("SYNC_IDLE_EVICTION_MS", defaults.idle_eviction_ms)?,
+        };
+        anyhow::ensure!(
+            sync.channel_capacity > 0,
+            "SYNC_CHANNEL_CAPACITY must be at least 1"
+        );
+        anyhow::ensure!(
+            sync.max_updates_per_sec > 0,
+            "SYNC_MAX_UPDATES_PER_SEC must be at least 1"
+        );
+        anyhow::ensure!(
+            sync.violation_window_secs > 0,
+            "SYNC_VIOLATION_WINDOW_SECS must be at least 1"
+        );
+
         Ok(Self {
             database_url,
             port,
             jwt_secret,
+            metrics_token,
+            sync,
         })
     }
 }
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> Result<T> {
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{} has an invalid value: {}", key, value)),
+        Err(_) => Ok(default),
+    }
+}
@@ -6,6 +6,8 @@ mod db;
 mod models;
 mod storage;
 mod sync;
+#[cfg(test)]
+mod test_support;
 
 use anyhow::Result;
 use api::auth::AppState;
@@ -14,6 +16,9 @@ use std::net::SocketAddr;
 use tower_http::cors::{Any, CorsLayer};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
+/// How long to wait for WebSocket sessions to close before flushing documents anyway
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize tracing (respect RUST_LOG via EnvFilter and include thread info)
@@ -37,13 +42,18 @@ async fn main() -> Result<()> {
     // Initialize database
     let pool = db::init(&config.database_url).await?;
 
-    // Start background cleanup job
-    // new thread
-    let pool_clone = pool.clone();
-    tokio::spawn(async move { cleanup::start_cleanup_job(pool_clone).await });
-
     // Initialize sync manager
-    let sync_manager = std::sync::Arc::new(sync::SyncManager::new());
+    let sync_manager =
+        std::sync::Arc::new(sync::SyncManager::new(pool.clone(), config.sync.clone()));
+    sync_manager.start_relay().await?;
+    let eviction_job = sync_manager.start_eviction();
+
+    // Start background update log compaction
+    let compaction_job = sync::start_compaction_job(
+        pool.clone(),
+        config.sync.clone(),
+        sync_manager.subscribe_shutdown(),
+    );
 
     // Initialize storage
     let storage = std::sync::Arc::new(storage::local::LocalStorage::new(
@@ -51,17 +61,26 @@ async fn main() -> Result<()> {
         10 * 1024 * 1024,
     )) as std::sync::Arc<dyn storage::BlobStorage>;
 
+    // Start background cleanup job
+    let cleanup_job = cleanup::start_cleanup_job(
+        pool.clone(),
+        storage.clone(),
+        sync_manager.subscribe_shutdown(),
+    );
+
     // Build app state
     let state = AppState {
         pool,
         config: config.clone(),
-        sync_manager,
+        sync_manager: sync_manager.clone(),
         storage,
     };
 
     // Build application router
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/metrics", get(api::metrics_handler))
+        .route("/ws", get(api::ws_multiplex_handler))
         .route("/ws/{doc}", get(api::ws_handler))
         .nest("/api/auth", api::auth_routes())
         .nest("/api/users", api::user_routes())
@@ -82,11 +101,51 @@ async fn main() -> Result<()> {
     tracing::info!("Listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            tracing::info!("Shutting down");
+            // Plain HTTP keeps being served while sockets close and documents are flushed
+            sync_manager.shutdown(SHUTDOWN_TIMEOUT).await;
+        })
+        .await?;
+
+    let _ = tokio::join!(cleanup_job, compaction_job, eviction_job);
+    tracing::info!("Shutdown complete");
 
     Ok(())
 }
 
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
 async fn health_check() -> &'static str {
     "OK"
 }
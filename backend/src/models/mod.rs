@@ -19,7 +19,7 @@ pub struct DocumentEdit {
     pub id: Uuid,
     pub subdoc_guid: String,
     pub user_id: Uuid,
-    pub session_id: Uuid,
+    pub session_id: Option<Uuid>,
     pub yjs_update: Vec<u8>,
     pub edit_type: Option<String>,
     pub block_type: Option<String>,
@@ -34,7 +34,7 @@ pub struct DocumentEditWithUser {
     pub id: Uuid,
     pub subdoc_guid: String,
     pub user_id: Uuid,
-    pub session_id: Uuid,
+    pub session_id: Option<Uuid>,
     pub yjs_update: Vec<u8>,
     pub edit_type: Option<String>,
     pub block_type: Option<String>,
@@ -93,11 +93,70 @@ pub struct DocumentMetadata {
     pub icon: Option<String>,
     pub description: Option<String>,
     pub tags: Vec<String>,
+    /// Free-form JSON object for client-specific settings
+    pub extra: serde_json::Value,
     pub parent_guid: Option<String>,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
 }
 
+/// Partial update of a document's metadata. Absent fields are left unchanged;
+/// `null` clears `icon` and `description`. `extra` is replaced as a whole.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DocumentMetadataPatch {
+    pub title: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    pub icon: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    pub description: Option<Option<String>>,
+    pub tags: Option<Vec<String>>,
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// What the sync server does when a client opens a guid the vault has no document for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum DocCreationPolicy {
+    /// Answer with `doc_not_found`; documents are only created through the REST API
+    Reject,
+    /// Save a new document of `doc_type` under `parent_guid` on the first edit
+    AutoCreate {
+        doc_type: String,
+        parent_guid: Option<String>,
+    },
+}
+
+impl DocCreationPolicy {
+    /// Build the policy from the `vaults` columns that store it
+    pub fn from_columns(policy: &str, doc_type: String, parent_guid: Option<String>) -> Self {
+        match policy {
+            "reject" => DocCreationPolicy::Reject,
+            _ => DocCreationPolicy::AutoCreate {
+                doc_type,
+                parent_guid,
+            },
+        }
+    }
+}
+
+/// Per-vault settings, managed by the vault's owners
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultSettings {
+    pub doc_creation: DocCreationPolicy,
+    /// Whether an owner's edit to a document in the trash restores it instead of being refused
+    #[serde(default)]
+    pub restore_on_edit: bool,
+}
+
+/// Tell a present `null` (`Some(None)`) apart from an absent field (`None`)
+fn deserialize_nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub email: String,
@@ -130,7 +189,7 @@ pub struct UpdateProfileRequest {
     pub avatar_url: Option<String>,
 }
 
-#[derive(Debug, Serialize, sqlx::FromRow)]
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
 pub struct PublicUserProfile {
     pub id: Uuid,
     pub username: Option<String>,
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// What an access change applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessScope {
+    /// Membership, ownership or the existence of a single vault changed
+    Vault(Uuid),
+    /// Organization membership changed, affecting every vault of the organization
+    Organization(Uuid),
+}
+
+/// Notification that someone's effective role on one or more vaults may have changed.
+/// Live sessions it matches re-check their role against the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessChange {
+    pub scope: AccessScope,
+    /// The affected user, or `None` for everyone with access to the scope
+    pub user_id: Option<Uuid>,
+}
+
+impl AccessChange {
+    pub fn vault(vault_id: Uuid, user_id: Option<Uuid>) -> Self {
+        Self {
+            scope: AccessScope::Vault(vault_id),
+            user_id,
+        }
+    }
+
+    pub fn organization(org_id: Uuid, user_id: Uuid) -> Self {
+        Self {
+            scope: AccessScope::Organization(org_id),
+            user_id: Some(user_id),
+        }
+    }
+
+    /// Whether a session of `user_id` on `vault_id` has to re-check its role.
+    ///
+    /// Sessions don't track which organization their vault belongs to, so an
+    /// organization change re-checks every session of the affected user.
+    pub fn affects(&self, vault_id: Uuid, user_id: Uuid) -> bool {
+        let in_scope = match self.scope {
+            AccessScope::Vault(id) => id == vault_id,
+            AccessScope::Organization(_) => true,
+        };
+        in_scope && self.user_id.is_none_or(|id| id == user_id)
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use yrs::sync::awareness::{AwarenessUpdate, AwarenessUpdateEntry};
+
+/// JSON state y-protocols uses to signal that a client went away
+const REMOVED_STATE: &str = "null";
+
+struct ClientAwareness {
+    clock: u32,
+    /// `None` once the client has been removed; the clock is kept so stale
+    /// updates for the same client id are still rejected
+    json: Option<Arc<str>>,
+    session_id: Uuid,
+}
+
+/// Latest awareness state (cursor, selection, user info) per Yjs client id of one document
+#[derive(Default)]
+pub struct DocumentAwareness {
+    clients: HashMap<u64, ClientAwareness>,
+}
+
+impl DocumentAwareness {
+    /// Merge an update sent by `session_id`, following the y-protocols clock rules.
+    /// Returns the entries that were actually newer, or `None` if nothing changed.
+    pub fn apply(&mut self, session_id: Uuid, update: AwarenessUpdate) -> Option<AwarenessUpdate> {
+        let mut accepted = HashMap::new();
+
+        for (client_id, entry) in update.clients {
+            let removed = &*entry.json == REMOVED_STATE;
+            let is_newer = match self.clients.get(&client_id) {
+                None => true,
+                Some(current) => {
+                    entry.clock > current.clock
+                        || (entry.clock == current.clock && removed && current.json.is_some())
+                }
+            };
+
+            if !is_newer {
+                continue;
+            }
+
+            self.clients.insert(
+                client_id,
+                ClientAwareness {
+                    clock: entry.clock,
+                    json: (!removed).then(|| entry.json.clone()),
+                    session_id,
+                },
+            );
+            accepted.insert(client_id, entry);
+        }
+
+        (!accepted.is_empty()).then_some(AwarenessUpdate { clients: accepted })
+    }
+
+    /// Current state of every known client, for clients that just joined
+    pub fn snapshot(&self) -> Option<AwarenessUpdate> {
+        let clients: HashMap<_, _> = self
+            .clients
+            .iter()
+            .filter_map(|(&client_id, client)| {
+                let json = client.json.clone()?;
+                Some((
+                    client_id,
+                    AwarenessUpdateEntry {
+                        clock: client.clock,
+                        json,
+                    },
+                ))
+            })
+            .collect();
+
+        (!clients.is_empty()).then_some(AwarenessUpdate { clients })
+    }
+
+    /// Mark every client announced by `session_id` as gone and return the
+    /// removal update to send to the remaining subscribers
+    pub fn remove_session(&mut self, session_id: Uuid) -> Option<AwarenessUpdate> {
+        let mut removed = HashMap::new();
+
+        for (&client_id, client) in self.clients.iter_mut() {
+            if client.session_id != session_id || client.json.is_none() {
+                continue;
+            }
+
+            client.clock += 1;
+            client.json = None;
+            removed.insert(
+                client_id,
+                AwarenessUpdateEntry {
+                    clock: client.clock,
+                    json: REMOVED_STATE.into(),
+                },
+            );
+        }
+
+        (!removed.is_empty()).then_some(AwarenessUpdate { clients: removed })
+    }
+
+    /// Whether any client is still present
+    pub fn is_empty(&self) -> bool {
+        self.clients.values().all(|client| client.json.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(entries: &[(u64, u32, &str)]) -> AwarenessUpdate {
+        AwarenessUpdate {
+            clients: entries
+                .iter()
+                .map(|&(client_id, clock, json)| {
+                    (
+                        client_id,
+                        AwarenessUpdateEntry {
+                            clock,
+                            json: json.into(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn stale_clocks_are_not_relayed() {
+        let session = Uuid::new_v4();
+        let mut awareness = DocumentAwareness::default();
+
+        assert!(
+            awareness
+                .apply(session, update(&[(1, 2, "{\"a\":1}")]))
+                .is_some()
+        );
+        assert!(
+            awareness
+                .apply(session, update(&[(1, 1, "{\"a\":0}")]))
+                .is_none()
+        );
+
+        let snapshot = awareness.snapshot().unwrap();
+        assert_eq!(&*snapshot.clients[&1].json, "{\"a\":1}");
+    }
+
+    #[test]
+    fn removing_a_session_bumps_clocks_and_clears_state() {
+        let leaving = Uuid::new_v4();
+        let staying = Uuid::new_v4();
+        let mut awareness = DocumentAwareness::default();
+        awareness.apply(leaving, update(&[(1, 3, "{}")]));
+        awareness.apply(staying, update(&[(2, 1, "{}")]));
+
+        let removal = awareness.remove_session(leaving).unwrap();
+        assert_eq!(removal.clients.len(), 1);
+        assert_eq!(removal.clients[&1].clock, 4);
+        assert_eq!(&*removal.clients[&1].json, REMOVED_STATE);
+
+        let snapshot = awareness.snapshot().unwrap();
+        assert!(!snapshot.clients.contains_key(&1));
+        assert!(snapshot.clients.contains_key(&2));
+        assert!(awareness.remove_session(leaving).is_none());
+    }
+}
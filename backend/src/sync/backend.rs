@@ -0,0 +1,280 @@
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use std::str::FromStr;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use uuid::Uuid;
+
+/// Which [`SyncBackend`] fans changes out between server instances
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncBackendKind {
+    /// Single instance; nothing leaves the process
+    #[default]
+    InMemory,
+    /// Postgres LISTEN/NOTIFY, for running several replicas
+    Postgres,
+}
+
+impl FromStr for SyncBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" | "in_memory" => Ok(Self::InMemory),
+            "postgres" => Ok(Self::Postgres),
+            other => Err(anyhow::anyhow!("Unknown sync backend: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncEventKind {
+    /// `data` is a Yjs update that was applied to the document
+    Update,
+    /// `data` is an awareness update that changed the document's presence state
+    Awareness,
+    /// An instance loaded the document; `data` is the state vector it loaded from the
+    /// database, and instances holding unflushed changes answer with an `Update`
+    Opened,
+    /// `data` is the document's new JSON `DocumentMetadata`
+    Metadata,
+    /// `data` is a JSON [`AccessChange`](super::AccessChange); `guid` is empty
+    AccessChanged,
+    /// The document was moved to or restored from the trash; `data` is a JSON
+    /// [`TrashChange`](super::TrashChange)
+    Trashed,
+    /// `data` is a JSON [`PresenceSnapshot`](super::presence::PresenceSnapshot) of the
+    /// sessions the publishing instance has open in a vault; `guid` is empty
+    Presence,
+}
+
+/// A change on one instance that the other instances have to apply and relay
+#[derive(Debug, Clone)]
+pub struct SyncEvent {
+    /// Instance that published the event
+    pub instance_id: Uuid,
+    pub guid: String,
+    /// Session that caused the change, so it is not echoed back to that client
+    pub origin: Option<Uuid>,
+    pub kind: SyncEventKind,
+    pub data: Vec<u8>,
+}
+
+/// Pub/sub transport that lets every server instance see every other instance's changes
+#[async_trait]
+pub trait SyncBackend: Send + Sync {
+    /// Publish an event to all instances, including (possibly) this one
+    async fn publish(&self, event: SyncEvent) -> anyhow::Result<()>;
+
+    /// Stream of events published by any instance
+    async fn subscribe(&self) -> anyhow::Result<BoxStream<'static, SyncEvent>>;
+}
+
+/// In-process bus; the default for single-instance deployments
+///
+/// Managers sharing one `InMemoryBackend` behave like separate instances, which is
+/// handy for exercising the cross-instance paths in tests.
+#[derive(Clone)]
+pub struct InMemoryBackend {
+    tx: broadcast::Sender<SyncEvent>,
+}
+
+impl Default for InMemoryBackend {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(1024);
+        Self { tx }
+    }
+}
+
+#[async_trait]
+impl SyncBackend for InMemoryBackend {
+    async fn publish(&self, event: SyncEvent) -> anyhow::Result<()> {
+        // No receivers just means nobody else is listening
+        let _ = self.tx.send(event);
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> anyhow::Result<BoxStream<'static, SyncEvent>> {
+        Ok(BroadcastStream::new(self.tx.subscribe())
+            .filter_map(|event| async move { event.ok() })
+            .boxed())
+    }
+}
+
+/// Channel all instances LISTEN on
+const NOTIFY_CHANNEL: &str = "sync_events";
+
+/// NOTIFY payloads have to stay under 8000 bytes; events whose serialized payload
+/// would not are stored in `sync_event_payloads` and only their id is sent
+const MAX_NOTIFY_BYTES: usize = 7900;
+
+/// JSON body of a NOTIFY on [`NOTIFY_CHANNEL`]
+#[derive(Serialize, Deserialize)]
+struct NotifyPayload {
+    instance_id: Uuid,
+    guid: String,
+    origin: Option<Uuid>,
+    kind: SyncEventKind,
+    /// Base64 event data, when small enough to send inline
+    data: Option<String>,
+    /// Row in `sync_event_payloads` holding the data otherwise
+    payload_id: Option<i64>,
+}
+
+/// Postgres LISTEN/NOTIFY backend for multi-instance deployments
+pub struct PostgresBackend {
+    pool: PgPool,
+}
+
+impl PostgresBackend {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn decode(&self, payload: &str) -> anyhow::Result<SyncEvent> {
+        let notify: NotifyPayload = serde_json::from_str(payload)?;
+
+        let data = match (notify.data, notify.payload_id) {
+            (Some(data), _) => BASE64.decode(data)?,
+            (None, Some(payload_id)) => {
+                sqlx::query_scalar!(
+                    "SELECT data FROM sync_event_payloads WHERE id = $1",
+                    payload_id
+                )
+                .fetch_one(&self.pool)
+                .await?
+            }
+            (None, None) => anyhow::bail!("Sync event without data"),
+        };
+
+        Ok(SyncEvent {
+            instance_id: notify.instance_id,
+            guid: notify.guid,
+            origin: notify.origin,
+            kind: notify.kind,
+            data,
+        })
+    }
+}
+
+#[async_trait]
+impl SyncBackend for PostgresBackend {
+    async fn publish(&self, event: SyncEvent) -> anyhow::Result<()> {
+        let mut notify = NotifyPayload {
+            instance_id: event.instance_id,
+            guid: event.guid,
+            origin: event.origin,
+            kind: event.kind,
+            data: Some(BASE64.encode(&event.data)),
+            payload_id: None,
+        };
+        let mut payload = serde_json::to_string(&notify)?;
+
+        if payload.len() > MAX_NOTIFY_BYTES {
+            let payload_id = sqlx::query_scalar!(
+                "INSERT INTO sync_event_payloads (data) VALUES ($1) RETURNING id",
+                event.data
+            )
+            .fetch_one(&self.pool)
+            .await?;
+            notify.data = None;
+            notify.payload_id = Some(payload_id);
+            payload = serde_json::to_string(&notify)?;
+        }
+        // Only an overlong guid can get here, and clients cannot send those
+        anyhow::ensure!(
+            payload.len() <= MAX_NOTIFY_BYTES,
+            "Sync event for guid of {} bytes does not fit in a notification",
+            notify.guid.len()
+        );
+
+        sqlx::query!("SELECT pg_notify($1, $2)", NOTIFY_CHANNEL, payload)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> anyhow::Result<BoxStream<'static, SyncEvent>> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+        tracing::info!("Listening for sync events on {}", NOTIFY_CHANNEL);
+
+        let (tx, rx) = mpsc::channel(1024);
+        let backend = Self::new(self.pool.clone());
+        tokio::spawn(async move {
+            loop {
+                // recv() reconnects on its own after the connection drops
+                let received = tokio::select! {
+                    received = listener.recv() => received,
+                    _ = tx.closed() => break,
+                };
+                let notification = match received {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        tracing::error!("Sync event listener failed: {:?}", e);
+                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                match backend.decode(notification.payload()).await {
+                    Ok(event) => {
+                        if tx.send(event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => tracing::error!("Dropping malformed sync event: {:?}", e),
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn postgres_backend_delivers_small_and_oversized_events(pool: PgPool) {
+        let publisher = PostgresBackend::new(pool.clone());
+        let mut events = PostgresBackend::new(pool).subscribe().await.unwrap();
+
+        let small = vec![1u8; 16];
+        let large: Vec<u8> = (0..MAX_NOTIFY_BYTES * 2).map(|i| i as u8).collect();
+        // Fits on its own once base64-encoded, but not with the rest of the payload
+        let borderline = vec![7u8; MAX_NOTIFY_BYTES * 3 / 4 - 8];
+        for data in [small.clone(), large.clone(), borderline.clone()] {
+            publisher
+                .publish(SyncEvent {
+                    instance_id: Uuid::new_v4(),
+                    guid: "pg-doc".to_string(),
+                    origin: None,
+                    kind: SyncEventKind::Update,
+                    data,
+                })
+                .await
+                .unwrap();
+        }
+
+        for expected in [small, large, borderline] {
+            let event = tokio::time::timeout(tokio::time::Duration::from_secs(5), events.next())
+                .await
+                .expect("event should arrive")
+                .unwrap();
+            assert_eq!(event.guid, "pg-doc");
+            assert_eq!(event.kind, SyncEventKind::Update);
+            assert_eq!(event.data, expected);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use sqlx::PgPool;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+
+use super::{SyncConfig, persistence};
+
+/// Periodically fold long update logs back into `subdocs.yjs_state`, until `shutdown`
+/// turns `true`
+pub fn start_compaction_job(
+    pool: PgPool,
+    config: SyncConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tick = interval(Duration::from_secs(config.compact_interval_secs));
+
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = shutdown.wait_for(|stopping| *stopping) => break,
+            }
+
+            if let Err(e) = compact_due_documents(&pool, &config).await {
+                tracing::error!("Compaction job failed: {:?}", e);
+            }
+        }
+        tracing::debug!("Compaction job stopped");
+    })
+}
+
+async fn compact_due_documents(pool: &PgPool, config: &SyncConfig) -> anyhow::Result<()> {
+    let guids = persistence::documents_needing_compaction(
+        pool,
+        config.compact_max_updates,
+        config.compact_max_bytes,
+    )
+    .await?;
+
+    for guid in guids {
+        // One broken document should not hold up the others
+        if let Err(e) = persistence::compact_document(pool, &guid).await {
+            tracing::error!("Failed to compact document {}: {:?}", guid, e);
+        }
+    }
+
+    Ok(())
+}
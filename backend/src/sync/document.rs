@@ -0,0 +1,473 @@
+use crate::models::DocumentMetadata;
+use sqlx::PgPool;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, GetString, ReadTxn, StateVector, Transact, Update};
+
+use super::persistence::{self, LoadedDocument};
+use super::{DocumentDeleted, LimitExceeded, SyncConfig};
+
+/// Capacity of a document actor's inbox
+const INBOX_CAPACITY: usize = 256;
+
+/// Text content of a document before and after an update was applied
+#[derive(Debug)]
+pub struct AppliedUpdate {
+    pub content_before: Option<String>,
+    pub content_after: Option<String>,
+    /// The update was the document's first and created its `subdocs` row
+    pub created: bool,
+}
+
+/// What a client is missing, as of the state vector the document had when it was encoded
+#[derive(Debug)]
+pub struct DocumentDiff {
+    pub update: Vec<u8>,
+    /// Server state the client will be at once it applied `update`
+    pub state_vector: StateVector,
+    pub metadata: DocumentMetadata,
+    /// The document is in the trash and takes no updates
+    pub deleted: bool,
+}
+
+/// An open document held in memory while clients are editing it
+///
+/// Updates are applied to `doc` directly and queued in `pending`; the owning actor
+/// appends them to the update log once the document has been idle for the debounce period.
+pub struct LiveDocument {
+    pub doc: Doc,
+    pub metadata: DocumentMetadata,
+    /// Whether a `subdocs` row exists for this document yet
+    pub persisted: bool,
+    /// Whether the document is in the trash; updates to it are refused
+    pub deleted: bool,
+    /// Last user whose update has not been flushed yet
+    pub last_editor: Option<Uuid>,
+    /// Encoded size of the document as of the last flush, plus every update applied since.
+    /// Updates that delete content still add to it, so it errs on the large side.
+    size: usize,
+    /// Local updates applied since the last flush
+    pending: Vec<Vec<u8>>,
+    /// When the oldest unflushed change was applied
+    dirty_since: Option<Instant>,
+    /// When the most recent change was applied
+    last_change: Instant,
+}
+
+impl LiveDocument {
+    pub fn new(loaded: LoadedDocument) -> Self {
+        let LoadedDocument {
+            doc,
+            metadata,
+            persisted,
+            deleted,
+        } = loaded;
+        let size = encoded_size(&doc);
+        Self {
+            doc,
+            metadata,
+            persisted,
+            deleted,
+            last_editor: None,
+            size,
+            pending: Vec::new(),
+            dirty_since: None,
+            last_change: Instant::now(),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_since.is_some()
+    }
+
+    /// Approximate encoded size of the document in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Apply a Yjs update and mark the document as having unflushed changes.
+    /// Returns `None` for updates that carry no changes, e.g. an empty sync step 2.
+    pub fn apply_update(
+        &mut self,
+        update_bytes: &[u8],
+        user_id: Uuid,
+    ) -> anyhow::Result<Option<AppliedUpdate>> {
+        let update = Update::decode_v1(update_bytes)?;
+        if update.is_empty() {
+            return Ok(None);
+        }
+
+        // Extract full document content BEFORE applying update
+        let content_before = extract_text_sample(&self.doc, usize::MAX);
+
+        {
+            let mut txn = self.doc.transact_mut();
+            txn.apply_update(update)?;
+        }
+
+        // Extract full document content AFTER applying update
+        let content_after = extract_text_sample(&self.doc, usize::MAX);
+        self.pending.push(update_bytes.to_vec());
+        self.size += update_bytes.len();
+
+        let now = Instant::now();
+        self.last_change = now;
+        self.dirty_since.get_or_insert(now);
+        self.last_editor = Some(user_id);
+
+        Ok(Some(AppliedUpdate {
+            content_before,
+            content_after,
+            created: false,
+        }))
+    }
+
+    /// Apply an update another instance already persists; it does not make this copy dirty
+    pub fn apply_remote_update(&mut self, update_bytes: &[u8]) -> anyhow::Result<()> {
+        let update = Update::decode_v1(update_bytes)?;
+        self.doc.transact_mut().apply_update(update)?;
+        self.size += update_bytes.len();
+        Ok(())
+    }
+
+    /// Encode everything the holder of `state_vector` is missing
+    pub fn encode_diff(&self, state_vector: &StateVector) -> DocumentDiff {
+        let txn = self.doc.transact();
+        DocumentDiff {
+            update: txn.encode_diff_v1(state_vector),
+            state_vector: txn.state_vector(),
+            metadata: self.metadata.clone(),
+            deleted: self.deleted,
+        }
+    }
+
+    /// Time left until pending changes should be flushed (zero when due)
+    pub fn flush_due_in(&self, config: &SyncConfig) -> Duration {
+        let dirty_since = self.dirty_since.unwrap_or(self.last_change);
+        let idle_deadline = self.last_change + Duration::from_millis(config.flush_debounce_ms);
+        let max_deadline = dirty_since + Duration::from_millis(config.flush_max_delay_ms);
+
+        idle_deadline
+            .min(max_deadline)
+            .saturating_duration_since(Instant::now())
+    }
+
+    /// Push the next flush out by a full debounce period, e.g. after a failed write
+    pub fn postpone_flush(&mut self) {
+        let now = Instant::now();
+        self.last_change = now;
+        if self.dirty_since.is_some() {
+            self.dirty_since = Some(now);
+        }
+    }
+
+    /// All pending updates merged into one, or `None` if there are none
+    pub fn merged_pending(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(yrs::merge_updates_v1(&self.pending)?))
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.size = encoded_size(&self.doc);
+        self.pending.clear();
+        self.dirty_since = None;
+        self.last_editor = None;
+        self.persisted = true;
+    }
+}
+
+/// Requests handled by a document actor, one at a time
+enum DocumentCommand {
+    ApplyUpdate {
+        update: Vec<u8>,
+        user_id: Uuid,
+        reply: oneshot::Sender<anyhow::Result<Option<AppliedUpdate>>>,
+    },
+    ApplyRemoteUpdate {
+        update: Vec<u8>,
+    },
+    EncodeDiff {
+        state_vector: StateVector,
+        reply: oneshot::Sender<DocumentDiff>,
+    },
+    Flush {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    /// Metadata changed in the database; it is sent along with future diffs
+    SetMetadata {
+        metadata: DocumentMetadata,
+    },
+    /// The document was moved to or restored from the trash
+    SetDeleted {
+        deleted: bool,
+    },
+}
+
+/// Cheap, cloneable handle to the task that owns a [`LiveDocument`]
+///
+/// All reads and writes of a document go through its actor's inbox, so updates
+/// from concurrent clients are applied and persisted strictly in order.
+#[derive(Clone)]
+pub struct DocumentHandle {
+    pub vault_id: Uuid,
+    tx: mpsc::Sender<DocumentCommand>,
+}
+
+impl DocumentHandle {
+    /// Spawn the actor owning `live_doc` and return a handle to it
+    pub fn spawn(pool: PgPool, config: SyncConfig, guid: String, live_doc: LiveDocument) -> Self {
+        let vault_id = live_doc.metadata.vault_id;
+        let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+
+        let actor = DocumentActor {
+            pool,
+            config,
+            guid,
+            live_doc,
+            inbox: rx,
+        };
+        tokio::spawn(actor.run());
+
+        Self { vault_id, tx }
+    }
+
+    pub async fn apply_update(
+        &self,
+        update: Vec<u8>,
+        user_id: Uuid,
+    ) -> anyhow::Result<Option<AppliedUpdate>> {
+        let (reply, rx) = oneshot::channel();
+        self.send(DocumentCommand::ApplyUpdate {
+            update,
+            user_id,
+            reply,
+        })
+        .await?;
+        rx.await?
+    }
+
+    /// Queue an update relayed from another instance
+    pub async fn apply_remote_update(&self, update: Vec<u8>) -> anyhow::Result<()> {
+        self.send(DocumentCommand::ApplyRemoteUpdate { update })
+            .await
+    }
+
+    pub async fn encode_diff(&self, state_vector: StateVector) -> anyhow::Result<DocumentDiff> {
+        let (reply, rx) = oneshot::channel();
+        self.send(DocumentCommand::EncodeDiff {
+            state_vector,
+            reply,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
+
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(DocumentCommand::Flush { reply }).await?;
+        rx.await?
+    }
+
+    pub async fn set_metadata(&self, metadata: DocumentMetadata) -> anyhow::Result<()> {
+        self.send(DocumentCommand::SetMetadata { metadata }).await
+    }
+
+    pub async fn set_deleted(&self, deleted: bool) -> anyhow::Result<()> {
+        self.send(DocumentCommand::SetDeleted { deleted }).await
+    }
+
+    async fn send(&self, command: DocumentCommand) -> anyhow::Result<()> {
+        self.tx
+            .send(command)
+            .await
+            .map_err(|_| anyhow::anyhow!("Document actor has stopped"))
+    }
+}
+
+struct DocumentActor {
+    pool: PgPool,
+    config: SyncConfig,
+    guid: String,
+    live_doc: LiveDocument,
+    inbox: mpsc::Receiver<DocumentCommand>,
+}
+
+impl DocumentActor {
+    async fn run(mut self) {
+        tracing::debug!("Document actor started for {}", self.guid);
+
+        loop {
+            let flush_in = self
+                .live_doc
+                .is_dirty()
+                .then(|| self.live_doc.flush_due_in(&self.config));
+
+            tokio::select! {
+                command = self.inbox.recv() => match command {
+                    Some(command) => self.handle(command).await,
+                    None => break,
+                },
+                _ = tokio::time::sleep(flush_in.unwrap_or_default()), if flush_in.is_some() => {
+                    if let Err(e) = self.persist().await {
+                        tracing::error!("Failed to flush document {}: {:?}", self.guid, e);
+                        self.live_doc.postpone_flush();
+                    }
+                }
+            }
+        }
+
+        // All handles are gone; make sure nothing is left unsaved
+        if self.live_doc.is_dirty()
+            && let Err(e) = self.persist().await
+        {
+            tracing::error!("Failed to flush document {}: {:?}", self.guid, e);
+        }
+        tracing::debug!("Document actor stopped for {}", self.guid);
+    }
+
+    async fn handle(&mut self, command: DocumentCommand) {
+        match command {
+            DocumentCommand::ApplyUpdate {
+                update,
+                user_id,
+                reply,
+            } => {
+                let result = self.apply_update(&update, user_id).await;
+                let _ = reply.send(result);
+            }
+            DocumentCommand::ApplyRemoteUpdate { update } => {
+                if let Err(e) = self.live_doc.apply_remote_update(&update) {
+                    tracing::error!(
+                        "Failed to apply remote update to document {}: {:?}",
+                        self.guid,
+                        e
+                    );
+                }
+            }
+            DocumentCommand::EncodeDiff {
+                state_vector,
+                reply,
+            } => {
+                let _ = reply.send(self.live_doc.encode_diff(&state_vector));
+            }
+            DocumentCommand::Flush { reply } => {
+                let result = if self.live_doc.is_dirty() {
+                    self.persist().await
+                } else {
+                    Ok(())
+                };
+                let _ = reply.send(result);
+            }
+            DocumentCommand::SetMetadata { metadata } => {
+                self.live_doc.metadata = metadata;
+            }
+            DocumentCommand::SetDeleted { deleted } => {
+                self.live_doc.deleted = deleted;
+            }
+        }
+    }
+
+    async fn apply_update(
+        &mut self,
+        update: &[u8],
+        user_id: Uuid,
+    ) -> anyhow::Result<Option<AppliedUpdate>> {
+        if self.live_doc.deleted {
+            return Err(self.deleted_error());
+        }
+
+        let size = self.live_doc.size() + update.len();
+        if size > self.config.max_document_bytes {
+            return Err(LimitExceeded::DocumentSize {
+                size,
+                max: self.config.max_document_bytes,
+            }
+            .into());
+        }
+
+        let mut applied = self.live_doc.apply_update(update, user_id)?;
+
+        if let Some(applied) = applied.as_mut()
+            && !self.live_doc.persisted
+        {
+            // New documents are written straight away so the row exists for audit logging
+            self.persist().await?;
+            if self.live_doc.deleted {
+                return Err(self.deleted_error());
+            }
+            applied.created = true;
+        }
+
+        Ok(applied)
+    }
+
+    fn deleted_error(&self) -> anyhow::Error {
+        DocumentDeleted {
+            guid: self.guid.clone(),
+        }
+        .into()
+    }
+
+    async fn persist(&mut self) -> anyhow::Result<()> {
+        let result = if !self.live_doc.persisted {
+            persistence::save_document(
+                &self.pool,
+                &self.guid,
+                &self.live_doc.doc,
+                self.live_doc.metadata.vault_id,
+                self.live_doc.last_editor,
+                &self.live_doc.metadata.doc_type,
+                self.live_doc.metadata.parent_guid.as_deref(),
+            )
+            .await
+        } else if let Some(update) = self.live_doc.merged_pending()? {
+            persistence::append_update(&self.pool, &self.guid, self.live_doc.last_editor, &update)
+                .await
+        } else {
+            Ok(())
+        };
+
+        match result {
+            // Trashed since it was loaded; the trash keeps the state as of deletion
+            Err(e) if e.is::<DocumentDeleted>() => {
+                tracing::warn!(
+                    "Document {} is in the trash, dropping {} unsaved updates",
+                    self.guid,
+                    self.live_doc.pending.len()
+                );
+                self.live_doc.deleted = true;
+            }
+            result => result?,
+        }
+        self.live_doc.mark_clean();
+        Ok(())
+    }
+}
+
+fn encoded_size(doc: &Doc) -> usize {
+    doc.transact()
+        .encode_state_as_update_v1(&StateVector::default())
+        .len()
+}
+
+/// Extract a text sample from the document for audit context
+/// This is a best-effort extraction that gets the first N characters
+pub fn extract_text_sample(doc: &Doc, max_chars: usize) -> Option<String> {
+    let txn = doc.transact();
+
+    // Tiptap's Collaboration extension uses "default" as the field name by default
+    if let Some(xml_fragment) = txn.get_xml_fragment("default") {
+        // Return raw XML for proper rendering on frontend
+        let xml_text = xml_fragment.get_string(&txn);
+        if !xml_text.is_empty() {
+            let sample = xml_text.chars().take(max_chars).collect::<String>();
+            return Some(sample);
+        }
+    }
+
+    None
+}
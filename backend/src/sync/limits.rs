@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+/// A client message that was refused because it went over one of the configured limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error("Frame of {size} bytes exceeds the limit of {max} bytes")]
+    FrameSize { size: usize, max: usize },
+    #[error("More than {max} updates per second")]
+    UpdateRate { max: u32 },
+    #[error("Document would grow to {size} bytes, over the limit of {max} bytes")]
+    DocumentSize { size: usize, max: usize },
+}
+
+/// Token bucket allowing `rate` events per second, in bursts of up to one second's worth
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn per_second(rate: u32) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token for one event, or fail if the bucket is empty
+    pub fn check(&mut self) -> Result<(), LimitExceeded> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return Err(LimitExceeded::UpdateRate { max: self.rate });
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Limit violations of the last `window`; older ones are forgiven, so only a burst of
+/// them counts against a long-lived session
+#[derive(Debug)]
+pub struct ViolationWindow {
+    window: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl ViolationWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.recent.push_back(Instant::now());
+    }
+
+    /// Violations within the window
+    pub fn count(&mut self) -> u32 {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.window)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn violations_outside_the_window_are_forgiven() {
+        let mut violations = ViolationWindow::new(Duration::from_millis(100));
+        violations.record();
+        violations.record();
+        assert_eq!(violations.count(), 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        violations.record();
+        assert_eq!(violations.count(), 3);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(violations.count(), 1);
+    }
+}
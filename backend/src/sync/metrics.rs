@@ -0,0 +1,122 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use super::{ActiveDocument, LimitExceeded};
+
+/// Counters for the sync server, rendered in the Prometheus text format by `/metrics`
+#[derive(Debug, Default)]
+pub struct SyncMetrics {
+    sessions_active: AtomicI64,
+    frames_received: AtomicU64,
+    bytes_received: AtomicU64,
+    updates_applied: AtomicU64,
+    frame_size_violations: AtomicU64,
+    update_rate_violations: AtomicU64,
+    document_size_violations: AtomicU64,
+    sessions_disconnected: AtomicU64,
+}
+
+impl SyncMetrics {
+    pub fn session_opened(&self) {
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_closed(&self) {
+        self.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn frame_received(&self, bytes: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn update_applied(&self) {
+        self.updates_applied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn limit_exceeded(&self, limit: &LimitExceeded) {
+        let counter = match limit {
+            LimitExceeded::FrameSize { .. } => &self.frame_size_violations,
+            LimitExceeded::UpdateRate { .. } => &self.update_rate_violations,
+            LimitExceeded::DocumentSize { .. } => &self.document_size_violations,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A session was closed for exceeding limits too often
+    pub fn session_disconnected(&self) {
+        self.sessions_disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the counters along with gauges for the documents currently held open
+    pub fn render(&self, documents: &[ActiveDocument]) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, i64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as i64;
+
+        metric(
+            "sync_sessions_active",
+            "gauge",
+            "Open WebSocket sessions.",
+            &[("", self.sessions_active.load(Ordering::Relaxed))],
+        );
+        metric(
+            "sync_documents_open",
+            "gauge",
+            "Documents with a broadcast channel or an in-memory copy.",
+            &[("", documents.len() as i64)],
+        );
+        metric(
+            "sync_documents_loaded",
+            "gauge",
+            "Documents held in memory.",
+            &[("", documents.iter().filter(|doc| doc.loaded).count() as i64)],
+        );
+        metric(
+            "sync_frames_received_total",
+            "counter",
+            "Binary frames received from clients.",
+            &[("", get(&self.frames_received))],
+        );
+        metric(
+            "sync_bytes_received_total",
+            "counter",
+            "Bytes of binary frames received from clients.",
+            &[("", get(&self.bytes_received))],
+        );
+        metric(
+            "sync_updates_applied_total",
+            "counter",
+            "Client updates applied to live documents.",
+            &[("", get(&self.updates_applied))],
+        );
+        metric(
+            "sync_limit_violations_total",
+            "counter",
+            "Client messages refused for exceeding a limit.",
+            &[
+                ("{limit=\"frame_size\"}", get(&self.frame_size_violations)),
+                ("{limit=\"update_rate\"}", get(&self.update_rate_violations)),
+                (
+                    "{limit=\"document_size\"}",
+                    get(&self.document_size_violations),
+                ),
+            ],
+        );
+        metric(
+            "sync_sessions_disconnected_total",
+            "counter",
+            "Sessions closed for exceeding limits too often.",
+            &[("", get(&self.sessions_disconnected))],
+        );
+
+        out
+    }
+}
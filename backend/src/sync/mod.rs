@@ -1,53 +1,1340 @@
-use std::collections::HashMap;
+mod access;
+mod awareness;
+mod backend;
+mod compaction;
+mod document;
+mod limits;
+mod metrics;
+mod persistence;
+mod presence;
+pub mod protocol;
+
+pub use access::AccessChange;
+pub use awareness::DocumentAwareness;
+pub use backend::{
+    InMemoryBackend, PostgresBackend, SyncBackend, SyncBackendKind, SyncEvent, SyncEventKind,
+};
+pub use compaction::start_compaction_job;
+
+pub use document::{AppliedUpdate, DocumentDiff, DocumentHandle, LiveDocument};
+pub use limits::{LimitExceeded, RateLimiter, ViolationWindow};
+pub use metrics::SyncMetrics;
+pub use presence::VaultPresence;
+
+use crate::models::{DocumentMetadata, DocumentMetadataPatch};
+use futures_util::StreamExt;
+use presence::{PRESENCE_HEARTBEAT, Presence, PresenceSnapshot};
+use protocol::{ControlMessage, Extension};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::{Mutex, RwLock, broadcast, watch};
+use uuid::Uuid;
+use yrs::sync::awareness::AwarenessUpdate;
+use yrs::sync::{Message, SyncMessage};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::{ReadTxn, StateVector, Transact, Update};
+
+/// Tuning knobs for document sync
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncConfig {
+    /// Flush a document once it has received no updates for this long
+    pub flush_debounce_ms: u64,
+    /// Flush a document at least this often while it is being edited continuously
+    pub flush_max_delay_ms: u64,
+    /// Messages buffered per document for slow subscribers before they have to resync
+    pub channel_capacity: usize,
+    /// How changes reach the other server instances
+    pub backend: SyncBackendKind,
+    /// Compact a document's update log once it holds this many entries...
+    pub compact_max_updates: i64,
+    /// ...or this many bytes
+    pub compact_max_bytes: i64,
+    /// How often to look for update logs that need compacting
+    pub compact_interval_secs: u64,
+    /// Largest message a client may send, not counting the multiplexing guid
+    pub max_frame_bytes: usize,
+    /// Updates a client may send per second, in bursts of up to one second's worth
+    pub max_updates_per_sec: u32,
+    /// Largest encoded state a document may grow to through client updates
+    pub max_document_bytes: usize,
+    /// Limit violations within `violation_window_secs` after which a session is disconnected
+    pub max_violations: u32,
+    /// How long a limit violation counts against its session
+    pub violation_window_secs: u64,
+    /// Drop a document's channel and in-memory copy once nobody has been subscribed for this long
+    pub idle_eviction_ms: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            flush_debounce_ms: 2_000,
+            flush_max_delay_ms: 10_000,
+            channel_capacity: 100,
+            backend: SyncBackendKind::default(),
+            compact_max_updates: 200,
+            compact_max_bytes: 1024 * 1024,
+            compact_interval_secs: 60,
+            max_frame_bytes: 1024 * 1024,
+            max_updates_per_sec: 50,
+            max_document_bytes: 16 * 1024 * 1024,
+            max_violations: 10,
+            violation_window_secs: 60,
+            idle_eviction_ms: 60_000,
+        }
+    }
+}
+
+/// The vault has no such document: it belongs to another vault, or it does not exist
+/// and the vault's creation policy rejects unknown guids
+#[derive(Debug, thiserror::Error)]
+#[error("Document {guid} does not exist in vault {vault_id}")]
+pub struct DocumentNotFound {
+    pub guid: String,
+    pub vault_id: Uuid,
+}
+
+/// The document is in the trash, so it takes no updates
+#[derive(Debug, thiserror::Error)]
+#[error("Document {guid} is in the trash")]
+pub struct DocumentDeleted {
+    pub guid: String,
+}
+
+/// A move the document tree cannot take
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidMove {
+    /// The new parent is not a live document of the vault
+    #[error("Parent document {0} does not exist")]
+    ParentNotFound(String),
+    /// The new parent is the document itself or one of its descendants
+    #[error("Moving document {guid} under {parent_guid} would make a cycle")]
+    Cycle { guid: String, parent_guid: String },
+}
+
+/// A document was moved to or restored from the trash
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TrashChange {
+    pub vault_id: Uuid,
+    pub deleted: bool,
+}
+
+/// A framed message fanned out to every client subscribed to a document
+#[derive(Debug, Clone)]
+pub struct BroadcastMessage {
+    /// Session the message came from; that session does not get it echoed back
+    pub origin: Option<Uuid>,
+    pub payload: Vec<u8>,
+}
+
+/// A framed message for every multiplexed connection of a vault, whether or not it is
+/// subscribed to the document the message is about
+#[derive(Debug, Clone)]
+pub struct VaultMessage {
+    pub guid: String,
+    pub payload: Vec<u8>,
+}
+
+/// A document this instance holds a broadcast channel or a live copy for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveDocument {
+    pub guid: String,
+    /// Receivers of the document's broadcast channel, i.e. subscribed sessions
+    pub subscribers: usize,
+    /// Whether the document is held in memory by an actor
+    pub loaded: bool,
+}
+
+/// Keeps a WebSocket session counted as open, and present in its vault, until it is dropped
+pub struct SessionGuard {
+    session_id: Uuid,
+    sessions: Arc<watch::Sender<usize>>,
+    presence: Arc<Presence>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.presence.leave(self.session_id);
+        self.sessions.send_modify(|count| *count -= 1);
+    }
+}
 
 /// Manages active document sync sessions
 pub struct SyncManager {
+    pool: PgPool,
+    config: SyncConfig,
+    /// Identifies this server instance in events sent through `backend`
+    instance_id: Uuid,
+    backend: Arc<dyn SyncBackend>,
     /// Map of document GUID -> document broadcast channel
-    documents: Arc<RwLock<HashMap<String, broadcast::Sender<Vec<u8>>>>>,
+    documents: Arc<RwLock<HashMap<String, broadcast::Sender<BroadcastMessage>>>>,
+    /// Map of vault ID -> channel for vault-wide listeners, e.g. file explorers
+    vaults: Arc<RwLock<HashMap<Uuid, broadcast::Sender<VaultMessage>>>>,
+    /// Map of document GUID -> actor owning the in-memory document
+    live: Arc<Mutex<HashMap<String, DocumentHandle>>>,
+    /// Map of document GUID -> lock that loads and evictions of the document take,
+    /// so they wait for each other without holding up other documents
+    loading: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    /// Map of document GUID -> awareness state of its connected clients
+    awareness: Arc<Mutex<HashMap<String, DocumentAwareness>>>,
+    /// Vault access changes that live sessions have to re-check their role for
+    access_changes: broadcast::Sender<AccessChange>,
+    metrics: SyncMetrics,
+    /// Flips to `true` once the server starts shutting down
+    shutdown: watch::Sender<bool>,
+    /// Number of open WebSocket sessions
+    sessions: Arc<watch::Sender<usize>>,
+    /// Which users the open sessions belong to and which documents they have open
+    presence: Arc<Presence>,
 }
 
 impl SyncManager {
-    pub fn new() -> Self {
+    pub fn new(pool: PgPool, config: SyncConfig) -> Self {
+        let backend: Arc<dyn SyncBackend> = match config.backend {
+            SyncBackendKind::InMemory => Arc::new(InMemoryBackend::default()),
+            SyncBackendKind::Postgres => Arc::new(PostgresBackend::new(pool.clone())),
+        };
+        Self::with_backend(pool, config, backend)
+    }
+
+    pub fn with_backend(pool: PgPool, config: SyncConfig, backend: Arc<dyn SyncBackend>) -> Self {
         Self {
+            pool,
+            config,
+            instance_id: Uuid::new_v4(),
+            backend,
             documents: Arc::new(RwLock::new(HashMap::new())),
+            vaults: Arc::new(RwLock::new(HashMap::new())),
+            live: Arc::new(Mutex::new(HashMap::new())),
+            loading: Arc::new(Mutex::new(HashMap::new())),
+            awareness: Arc::new(Mutex::new(HashMap::new())),
+            access_changes: broadcast::channel(64).0,
+            metrics: SyncMetrics::default(),
+            shutdown: watch::channel(false).0,
+            sessions: Arc::new(watch::channel(0).0),
+            presence: Arc::new(Presence::default()),
         }
     }
 
+    pub fn metrics(&self) -> &SyncMetrics {
+        &self.metrics
+    }
+
+    /// Resolves to `true` once the server starts shutting down; sessions and
+    /// background jobs watch it to wind down
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Count a session as open, and its user as present in the vault, until the
+    /// returned guard is dropped
+    pub fn track_session(&self, session_id: Uuid, user_id: Uuid, vault_id: Uuid) -> SessionGuard {
+        self.sessions.send_modify(|count| *count += 1);
+        self.presence.join(session_id, user_id, vault_id);
+        SessionGuard {
+            session_id,
+            sessions: self.sessions.clone(),
+            presence: self.presence.clone(),
+        }
+    }
+
+    /// Record that a tracked session subscribed to (`open`) or left a document
+    pub fn set_document_presence(&self, session_id: Uuid, guid: &str, open: bool) {
+        if open {
+            self.presence.open_document(session_id, guid);
+        } else {
+            self.presence.close_document(session_id, guid);
+        }
+    }
+
+    /// Users connected to a vault through any instance, per document they have open
+    pub fn vault_presence(&self, vault_id: Uuid) -> VaultPresence {
+        self.presence.vault(vault_id)
+    }
+
+    /// Ask every session and background job to stop, wait up to `timeout` for the
+    /// sessions to close, then flush every document still held in memory
+    pub async fn shutdown(&self, timeout: tokio::time::Duration) {
+        self.shutdown.send_replace(true);
+
+        let mut sessions = self.sessions.subscribe();
+        if tokio::time::timeout(timeout, sessions.wait_for(|count| *count == 0))
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "{} sessions still open after {:?}, flushing anyway",
+                *sessions.borrow(),
+                timeout
+            );
+        }
+
+        let handles: Vec<(String, DocumentHandle)> = self
+            .live
+            .lock()
+            .await
+            .iter()
+            .map(|(guid, handle)| (guid.clone(), handle.clone()))
+            .collect();
+        for (guid, handle) in handles {
+            if let Err(e) = handle.flush().await {
+                tracing::error!("Failed to flush document {} on shutdown: {:?}", guid, e);
+            }
+        }
+        tracing::info!("Sync manager shut down");
+    }
+
     /// Get or create a broadcast channel for a document
-    pub async fn get_document_channel(&self, guid: &str) -> broadcast::Sender<Vec<u8>> {
+    pub async fn get_document_channel(&self, guid: &str) -> broadcast::Sender<BroadcastMessage> {
         let mut docs = self.documents.write().await;
 
         docs.entry(guid.to_string())
             .or_insert_with(|| {
-                let (tx, _rx) = broadcast::channel(100);
+                let (tx, _rx) = broadcast::channel(self.config.channel_capacity);
                 tracing::info!("Created broadcast channel for document: {}", guid);
                 tx
             })
             .clone()
     }
 
-    /// Broadcast an update to all clients subscribed to a document
-    pub async fn broadcast_update(&self, guid: &str, update: Vec<u8>) -> Result<(), String> {
+    /// Broadcast an update to all clients subscribed to a document except `origin`
+    pub async fn broadcast_update(
+        &self,
+        guid: &str,
+        origin: Option<Uuid>,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
         let docs = self.documents.read().await;
 
         if let Some(tx) = docs.get(guid) {
-            tx.send(update)
+            tx.send(BroadcastMessage { origin, payload })
                 .map_err(|e| format!("Failed to broadcast update: {}", e))?;
         }
 
         Ok(())
     }
 
+    /// Apply and relay changes published by other instances until the backend stream ends
+    pub async fn start_relay(self: &Arc<Self>) -> anyhow::Result<()> {
+        let mut events = self.backend.subscribe().await?;
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                if event.instance_id == manager.instance_id {
+                    continue;
+                }
+                if let Err(e) = manager.handle_remote(&event).await {
+                    tracing::error!(
+                        "Failed to handle {:?} event for {} from instance {}: {:?}",
+                        event.kind,
+                        event.guid,
+                        event.instance_id,
+                        e
+                    );
+                }
+            }
+            tracing::warn!("Sync event stream ended");
+        });
+        self.start_presence_announcer();
+
+        tracing::info!(
+            "Relaying sync events for instance {} ({:?} backend)",
+            self.instance_id,
+            self.config.backend
+        );
+        Ok(())
+    }
+
+    /// Tell the other instances who is connected through this one: a vault's sessions
+    /// whenever they change, and all of them every PRESENCE_HEARTBEAT
+    fn start_presence_announcer(self: &Arc<Self>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut heartbeat = tokio::time::interval(PRESENCE_HEARTBEAT);
+            loop {
+                let snapshots = tokio::select! {
+                    _ = manager.presence.wait_for_change() => manager.presence.take_changed(),
+                    _ = heartbeat.tick() => manager.presence.take_all(),
+                };
+                for snapshot in snapshots {
+                    if let Err(e) = manager.publish_presence(&snapshot).await {
+                        tracing::error!(
+                            "Failed to announce presence in vault {}: {:?}",
+                            snapshot.vault_id,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    async fn publish_presence(&self, snapshot: &PresenceSnapshot) -> anyhow::Result<()> {
+        self.publish(
+            "",
+            None,
+            SyncEventKind::Presence,
+            serde_json::to_vec(snapshot)?,
+        )
+        .await
+    }
+
+    /// Periodically evict documents and vault channels nobody has been subscribed to for
+    /// `idle_eviction_ms`, until shutdown
+    pub fn start_eviction(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let manager = self.clone();
+        let mut shutdown = self.subscribe_shutdown();
+        let grace = tokio::time::Duration::from_millis(self.config.idle_eviction_ms);
+
+        tokio::spawn(async move {
+            let mut tick =
+                tokio::time::interval((grace / 2).max(tokio::time::Duration::from_millis(100)));
+            // When each document was first seen without subscribers
+            let mut idle_since = HashMap::new();
+
+            loop {
+                tokio::select! {
+                    _ = tick.tick() => {}
+                    _ = async { shutdown.wait_for(|stopping| *stopping).await.map(drop) } => break,
+                }
+                idle_since = manager.evict_idle(grace, idle_since).await;
+            }
+        })
+    }
+
+    /// Evict documents that have had no subscribers since before `grace` ago.
+    /// Returns when the remaining idle documents were first seen idle.
+    async fn evict_idle(
+        &self,
+        grace: tokio::time::Duration,
+        idle_since: HashMap<String, tokio::time::Instant>,
+    ) -> HashMap<String, tokio::time::Instant> {
+        let now = tokio::time::Instant::now();
+        let mut still_idle = HashMap::new();
+
+        for document in self.active_documents().await {
+            if document.subscribers > 0 {
+                continue;
+            }
+            let since = idle_since.get(&document.guid).copied().unwrap_or(now);
+            if now.duration_since(since) >= grace {
+                self.evict(&document.guid).await;
+            } else {
+                still_idle.insert(document.guid, since);
+            }
+        }
+
+        self.vaults
+            .write()
+            .await
+            .retain(|_, tx| tx.receiver_count() > 0);
+
+        still_idle
+    }
+
+    /// Drop a document's channel, awareness and live copy, flushing it first,
+    /// unless a session subscribed to it in the meantime
+    async fn evict(&self, guid: &str) {
+        {
+            let mut docs = self.documents.write().await;
+            if docs.get(guid).is_some_and(|tx| tx.receiver_count() > 0) {
+                return;
+            }
+            docs.remove(guid);
+        }
+
+        // Flush without holding any lock, so other documents carry on meanwhile
+        let handle = self.live.lock().await.get(guid).cloned();
+        if let Some(handle) = handle
+            && let Err(e) = handle.flush().await
+        {
+            // Keep the unsaved changes around; the actor retries on its own
+            tracing::error!("Failed to flush idle document {}: {:?}", guid, e);
+            return;
+        }
+
+        // Sessions that open the document meanwhile wait on its lock until the last
+        // changes are flushed, so they load the latest state from the database
+        let lock = self.document_lock(guid).await;
+        let evicted = {
+            let _evicting = lock.lock().await;
+            let removed = {
+                let mut live = self.live.lock().await;
+                if self.has_subscribers(guid).await {
+                    None
+                } else {
+                    Some(live.remove(guid))
+                }
+            };
+            match removed {
+                // Catch edits applied since the first flush
+                Some(Some(handle)) => {
+                    if let Err(e) = handle.flush().await {
+                        tracing::error!("Failed to flush evicted document {}: {:?}", guid, e);
+                    }
+                    true
+                }
+                Some(None) => true,
+                None => false,
+            }
+        };
+        self.release_document_lock(guid, lock).await;
+        if !evicted {
+            return;
+        }
+
+        self.awareness.lock().await.remove(guid);
+        tracing::debug!("Evicted idle document {}", guid);
+    }
+
+    /// Documents with a broadcast channel or a live copy on this instance, by guid
+    pub async fn active_documents(&self) -> Vec<ActiveDocument> {
+        // Never hold both locks at once; `evict` takes them in the opposite order
+        let mut documents: BTreeMap<String, ActiveDocument> = self
+            .documents
+            .read()
+            .await
+            .iter()
+            .map(|(guid, tx)| {
+                let document = ActiveDocument {
+                    guid: guid.clone(),
+                    subscribers: tx.receiver_count(),
+                    loaded: false,
+                };
+                (guid.clone(), document)
+            })
+            .collect();
+
+        for guid in self.live.lock().await.keys() {
+            documents
+                .entry(guid.clone())
+                .or_insert_with(|| ActiveDocument {
+                    guid: guid.clone(),
+                    subscribers: 0,
+                    loaded: true,
+                })
+                .loaded = true;
+        }
+
+        documents.into_values().collect()
+    }
+
+    async fn handle_remote(&self, event: &SyncEvent) -> anyhow::Result<()> {
+        match event.kind {
+            SyncEventKind::Update => {
+                let handle = self.live.lock().await.get(&event.guid).cloned();
+                // Nobody on this instance has the document open
+                let Some(handle) = handle else {
+                    return Ok(());
+                };
+
+                handle.apply_remote_update(event.data.clone()).await?;
+                let msg = Message::Sync(SyncMessage::Update(event.data.clone())).encode_v1();
+                let _ = self.broadcast_update(&event.guid, event.origin, msg).await;
+            }
+            SyncEventKind::Awareness => {
+                let Some(origin) = event.origin else {
+                    return Ok(());
+                };
+                if !self.has_subscribers(&event.guid).await {
+                    return Ok(());
+                }
+
+                if let Some(changed) = self
+                    .apply_awareness(
+                        &event.guid,
+                        origin,
+                        AwarenessUpdate::decode_v1(&event.data)?,
+                    )
+                    .await
+                {
+                    let msg = Message::Awareness(changed).encode_v1();
+                    let _ = self.broadcast_update(&event.guid, Some(origin), msg).await;
+                }
+            }
+            SyncEventKind::Opened => {
+                let handle = self.live.lock().await.get(&event.guid).cloned();
+                let Some(handle) = handle else {
+                    return Ok(());
+                };
+
+                // Hand over whatever we have not flushed yet, so the other instance
+                // does not serve its clients a stale copy from the database
+                let diff = handle
+                    .encode_diff(StateVector::decode_v1(&event.data)?)
+                    .await?;
+                if Update::decode_v1(&diff.update)?.is_empty() {
+                    return Ok(());
+                }
+                self.publish(&event.guid, None, SyncEventKind::Update, diff.update)
+                    .await?;
+            }
+            SyncEventKind::Metadata => {
+                let metadata: DocumentMetadata = serde_json::from_slice(&event.data)?;
+                let handle = self.live.lock().await.get(&event.guid).cloned();
+                if let Some(handle) = handle {
+                    handle.set_metadata(metadata.clone()).await?;
+                }
+                self.broadcast_metadata(&metadata).await?;
+            }
+            SyncEventKind::AccessChanged => {
+                let change: AccessChange = serde_json::from_slice(&event.data)?;
+                let _ = self.access_changes.send(change);
+            }
+            SyncEventKind::Trashed => {
+                let change: TrashChange = serde_json::from_slice(&event.data)?;
+                self.apply_trash_change(&event.guid, change).await?;
+            }
+            SyncEventKind::Presence => {
+                let snapshot: PresenceSnapshot = serde_json::from_slice(&event.data)?;
+                self.presence.apply_remote(event.instance_id, snapshot);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        guid: &str,
+        origin: Option<Uuid>,
+        kind: SyncEventKind,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.backend
+            .publish(SyncEvent {
+                instance_id: self.instance_id,
+                guid: guid.to_string(),
+                origin,
+                kind,
+                data,
+            })
+            .await
+    }
+
+    /// Send an update applied by `origin` to the document's subscribers on every instance
+    pub async fn publish_update(
+        &self,
+        guid: &str,
+        origin: Uuid,
+        update: &[u8],
+    ) -> anyhow::Result<()> {
+        let msg = Message::Sync(SyncMessage::Update(update.to_vec())).encode_v1();
+        self.broadcast_update(guid, Some(origin), msg)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        self.publish(guid, Some(origin), SyncEventKind::Update, update.to_vec())
+            .await
+    }
+
+    /// Send awareness changes announced by `origin` to the document's subscribers on every instance
+    pub async fn publish_awareness(
+        &self,
+        guid: &str,
+        origin: Uuid,
+        changed: AwarenessUpdate,
+    ) -> anyhow::Result<()> {
+        let data = changed.encode_v1();
+        let msg = Message::Awareness(changed).encode_v1();
+        // Nobody left on this instance is fine, others may still be listening
+        let _ = self.broadcast_update(guid, Some(origin), msg).await;
+        self.publish(guid, Some(origin), SyncEventKind::Awareness, data)
+            .await
+    }
+
+    /// Tell live sessions on every instance that `change` may have altered their role.
+    /// Call after the change is committed, so the sessions' re-check sees it.
+    pub async fn notify_access_change(&self, change: AccessChange) {
+        // No receivers just means nobody on this instance is connected
+        let _ = self.access_changes.send(change);
+
+        let published = match serde_json::to_vec(&change) {
+            Ok(data) => {
+                self.publish("", None, SyncEventKind::AccessChanged, data)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = published {
+            tracing::error!("Failed to publish access change {:?}: {:?}", change, e);
+        }
+    }
+
+    /// Subscribe to access changes on this and every other instance
+    pub fn subscribe_access_changes(&self) -> broadcast::Receiver<AccessChange> {
+        self.access_changes.subscribe()
+    }
+
+    /// Subscribe to messages for every document of a vault
+    pub async fn subscribe_vault(&self, vault_id: Uuid) -> broadcast::Receiver<VaultMessage> {
+        let mut vaults = self.vaults.write().await;
+        vaults
+            .entry(vault_id)
+            .or_insert_with(|| broadcast::channel(self.config.channel_capacity).0)
+            .subscribe()
+    }
+
+    /// Apply a metadata patch to a document and send the result to its subscribers and
+    /// the vault's listeners on every instance
+    pub async fn update_metadata(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+        patch: &DocumentMetadataPatch,
+    ) -> anyhow::Result<DocumentMetadata> {
+        let metadata = persistence::update_metadata(&self.pool, guid, vault_id, patch)
+            .await?
+            .ok_or_else(|| DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            })?;
+        self.publish_metadata(&metadata).await?;
+
+        Ok(metadata)
+    }
+
+    /// Move a document under `parent_guid` (`None`: to the top level) and send the
+    /// resulting metadata to its subscribers and the vault's listeners on every instance
+    pub async fn move_document(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+        parent_guid: Option<&str>,
+    ) -> anyhow::Result<DocumentMetadata> {
+        let metadata = persistence::move_document(&self.pool, guid, vault_id, parent_guid)
+            .await?
+            .ok_or_else(|| DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            })?;
+        self.publish_metadata(&metadata).await?;
+
+        Ok(metadata)
+    }
+
+    /// Hand changed metadata to the document's live copy, if it is loaded, and send it
+    /// to this instance's listeners and every other instance
+    async fn publish_metadata(&self, metadata: &DocumentMetadata) -> anyhow::Result<()> {
+        let handle = self.live.lock().await.get(&metadata.guid).cloned();
+        if let Some(handle) = handle {
+            handle.set_metadata(metadata.clone()).await?;
+        }
+        self.broadcast_metadata(metadata).await?;
+        self.publish(
+            &metadata.guid,
+            None,
+            SyncEventKind::Metadata,
+            serde_json::to_vec(metadata)?,
+        )
+        .await
+    }
+
+    /// Send metadata to this instance's subscribers of the document and listeners of its vault
+    async fn broadcast_metadata(&self, metadata: &DocumentMetadata) -> anyhow::Result<()> {
+        let msg = Extension::Metadata(serde_json::to_string(metadata)?).encode();
+        self.broadcast_to_vault(&metadata.guid, metadata.vault_id, msg)
+            .await;
+        Ok(())
+    }
+
+    /// Send a message about a document to this instance's subscribers of the document
+    /// and listeners of its vault
+    async fn broadcast_to_vault(&self, guid: &str, vault_id: Uuid, msg: Vec<u8>) {
+        // The client that made the change gets it too, as confirmation
+        let _ = self.broadcast_update(guid, None, msg.clone()).await;
+
+        let vaults = self.vaults.read().await;
+        if let Some(tx) = vaults.get(&vault_id) {
+            let _ = tx.send(VaultMessage {
+                guid: guid.to_string(),
+                payload: msg,
+            });
+        }
+    }
+
+    /// Tell live copies, subscribers and vault listeners on every instance that documents
+    /// were moved to or restored from the trash. Call after the change is committed.
+    pub async fn notify_trash_change(&self, guids: &[String], change: TrashChange) {
+        for guid in guids {
+            let notified = match self.apply_trash_change(guid, change).await {
+                Ok(()) => match serde_json::to_vec(&change) {
+                    Ok(data) => self.publish(guid, None, SyncEventKind::Trashed, data).await,
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = notified {
+                tracing::error!(
+                    "Failed to notify trash change {:?} of document {}: {:?}",
+                    change,
+                    guid,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Take a document out of the trash and tell everyone who has it open.
+    /// Returns whether it was in the trash.
+    pub async fn restore_document(&self, guid: &str, vault_id: Uuid) -> anyhow::Result<bool> {
+        let restored = persistence::restore_document(&self.pool, guid, vault_id).await?;
+        if restored {
+            self.notify_trash_change(
+                &[guid.to_string()],
+                TrashChange {
+                    vault_id,
+                    deleted: false,
+                },
+            )
+            .await;
+        }
+        Ok(restored)
+    }
+
+    async fn apply_trash_change(&self, guid: &str, change: TrashChange) -> anyhow::Result<()> {
+        let handle = self.live.lock().await.get(guid).cloned();
+        if let Some(handle) = handle {
+            handle.set_deleted(change.deleted).await?;
+        }
+
+        let control = if change.deleted {
+            ControlMessage::DocDeleted
+        } else {
+            ControlMessage::DocRestored
+        };
+        let msg = Extension::Control(control).encode();
+        self.broadcast_to_vault(guid, change.vault_id, msg).await;
+        Ok(())
+    }
+
     /// Subscribe to document updates
-    pub async fn subscribe(&self, guid: &str) -> broadcast::Receiver<Vec<u8>> {
+    pub async fn subscribe(&self, guid: &str) -> broadcast::Receiver<BroadcastMessage> {
         self.get_document_channel(guid).await.subscribe()
     }
+
+    /// Called after a client dropped its subscription to a document.
+    /// Flushes pending changes once nobody is subscribed anymore.
+    pub async fn release(&self, guid: &str) {
+        if !self.has_subscribers(guid).await {
+            tracing::debug!("Last client left document {}, flushing", guid);
+            if let Err(e) = self.flush(guid).await {
+                tracing::error!("Failed to flush document {}: {:?}", guid, e);
+            }
+        }
+    }
+
+    async fn has_subscribers(&self, guid: &str) -> bool {
+        let docs = self.documents.read().await;
+        docs.get(guid).is_some_and(|tx| tx.receiver_count() > 0)
+    }
+
+    /// Get a handle to the actor owning a document, loading it from the database if needed
+    pub async fn open_document(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+    ) -> anyhow::Result<DocumentHandle> {
+        if let Some(existing) = self.live_handle(guid, vault_id).await? {
+            return Ok(existing);
+        }
+
+        let lock = self.document_lock(guid).await;
+        let result = {
+            let _loading = lock.lock().await;
+            self.load_document(guid, vault_id).await
+        };
+        self.release_document_lock(guid, lock).await;
+        result
+    }
+
+    /// The live copy of a document, if this instance holds one
+    async fn live_handle(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+    ) -> anyhow::Result<Option<DocumentHandle>> {
+        match self.live.lock().await.get(guid) {
+            Some(existing) if existing.vault_id != vault_id => Err(DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            }
+            .into()),
+            existing => Ok(existing.cloned()),
+        }
+    }
+
+    /// Load a document and spawn its actor; the caller holds the document's lock
+    async fn load_document(&self, guid: &str, vault_id: Uuid) -> anyhow::Result<DocumentHandle> {
+        // Someone else may have loaded it while we waited for the lock
+        if let Some(existing) = self.live_handle(guid, vault_id).await? {
+            return Ok(existing);
+        }
+
+        let loaded = persistence::load_or_create_document(&self.pool, guid, vault_id).await?;
+        let loaded_state = loaded.doc.transact().state_vector().encode_v1();
+        let handle = DocumentHandle::spawn(
+            self.pool.clone(),
+            self.config.clone(),
+            guid.to_string(),
+            LiveDocument::new(loaded),
+        );
+        self.live
+            .lock()
+            .await
+            .insert(guid.to_string(), handle.clone());
+        tracing::debug!("Opened live document {} for vault {}", guid, vault_id);
+
+        // Other instances may hold edits the database does not have yet
+        if let Err(e) = self
+            .publish(guid, None, SyncEventKind::Opened, loaded_state)
+            .await
+        {
+            tracing::error!("Failed to announce opened document {}: {:?}", guid, e);
+        }
+
+        Ok(handle)
+    }
+
+    async fn document_lock(&self, guid: &str) -> Arc<Mutex<()>> {
+        self.loading
+            .lock()
+            .await
+            .entry(guid.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Drop the document's lock from the map once nobody else is waiting for it
+    async fn release_document_lock(&self, guid: &str, lock: Arc<Mutex<()>>) {
+        let mut loading = self.loading.lock().await;
+        drop(lock);
+        // Clones are only made under the map's lock, so the count cannot grow meanwhile
+        if loading
+            .get(guid)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            loading.remove(guid);
+        }
+    }
+
+    /// Encode the diff a client with `state_vector` is missing, plus the document metadata
+    pub async fn encode_diff(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+        state_vector: StateVector,
+    ) -> anyhow::Result<DocumentDiff> {
+        self.open_document(guid, vault_id)
+            .await?
+            .encode_diff(state_vector)
+            .await
+    }
+
+    /// Apply an update to the in-memory document; it is persisted by the document's actor.
+    /// Returns `None` if the update did not contain any changes.
+    pub async fn apply_update(
+        &self,
+        guid: &str,
+        vault_id: Uuid,
+        user_id: Uuid,
+        update: &[u8],
+    ) -> anyhow::Result<Option<AppliedUpdate>> {
+        self.open_document(guid, vault_id)
+            .await?
+            .apply_update(update.to_vec(), user_id)
+            .await
+    }
+
+    /// Record an awareness update sent by a session.
+    /// Returns the entries that changed and should be relayed to other clients.
+    pub async fn apply_awareness(
+        &self,
+        guid: &str,
+        session_id: Uuid,
+        update: AwarenessUpdate,
+    ) -> Option<AwarenessUpdate> {
+        let mut awareness = self.awareness.lock().await;
+        let doc_awareness = awareness.entry(guid.to_string()).or_default();
+        let changed = doc_awareness.apply(session_id, update);
+
+        if doc_awareness.is_empty() {
+            awareness.remove(guid);
+        }
+
+        changed
+    }
+
+    /// Awareness state of every client currently present in a document
+    pub async fn awareness_snapshot(&self, guid: &str) -> Option<AwarenessUpdate> {
+        let awareness = self.awareness.lock().await;
+        awareness.get(guid)?.snapshot()
+    }
+
+    /// Drop the awareness state a session announced for a document.
+    /// Returns the removal update to broadcast, if the session had any clients.
+    pub async fn remove_awareness(&self, guid: &str, session_id: Uuid) -> Option<AwarenessUpdate> {
+        let mut awareness = self.awareness.lock().await;
+        let doc_awareness = awareness.get_mut(guid)?;
+        let removal = doc_awareness.remove_session(session_id);
+
+        if doc_awareness.is_empty() {
+            awareness.remove(guid);
+        }
+
+        removal
+    }
+
+    /// Write pending changes of a document to the database right away
+    pub async fn flush(&self, guid: &str) -> anyhow::Result<()> {
+        let handle = self.live.lock().await.get(guid).cloned();
+
+        match handle {
+            Some(handle) => handle.flush().await,
+            None => Ok(()),
+        }
+    }
 }
 
-impl Default for SyncManager {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_user, create_vault};
+    use yrs::{Doc, GetString, Text, Transact};
+
+    const CLIENTS: u64 = 8;
+    const UPDATES_PER_CLIENT: u64 = 25;
+
+    #[sqlx::test]
+    async fn concurrent_updates_are_all_persisted(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let manager = Arc::new(SyncManager::new(pool.clone(), SyncConfig::default()));
+        let guid = "stress-doc";
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for client in 0..CLIENTS {
+            let manager = manager.clone();
+            tasks.spawn(async move {
+                let doc = Doc::with_client_id(client + 1);
+                let text = doc.get_or_insert_text("content");
+                for i in 0..UPDATES_PER_CLIENT {
+                    let update = {
+                        let mut txn = doc.transact_mut();
+                        text.push(&mut txn, &format!("[{}:{}]", client, i));
+                        txn.encode_update_v1()
+                    };
+                    manager
+                        .apply_update(guid, vault_id, user_id, &update)
+                        .await
+                        .expect("update should apply");
+                }
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.expect("client task panicked");
+        }
+
+        manager.flush(guid).await.expect("flush should succeed");
+
+        let loaded = persistence::load_or_create_document(&pool, guid, vault_id)
+            .await
+            .expect("document should load");
+        assert!(loaded.persisted);
+
+        let doc = loaded.doc;
+        let text = doc.get_or_insert_text("content");
+        let content = text.get_string(&doc.transact());
+        for client in 0..CLIENTS {
+            for i in 0..UPDATES_PER_CLIENT {
+                let marker = format!("[{}:{}]", client, i);
+                assert!(content.contains(&marker), "missing update {}", marker);
+            }
+        }
+    }
+
+    fn push_text(doc: &Doc, content: &str) -> Vec<u8> {
+        let text = doc.get_or_insert_text("content");
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, content);
+        txn.encode_update_v1()
+    }
+
+    async fn live_text(manager: &SyncManager, guid: &str, vault_id: Uuid) -> String {
+        let diff = manager
+            .encode_diff(guid, vault_id, StateVector::default())
+            .await
+            .unwrap();
+        let doc = Doc::new();
+        doc.transact_mut()
+            .apply_update(Update::decode_v1(&diff.update).unwrap())
+            .unwrap();
+        let text = doc.get_or_insert_text("content");
+        text.get_string(&doc.transact())
+    }
+
+    /// Two managers sharing one bus, standing in for two server instances
+    async fn two_instances(pool: &PgPool) -> (Arc<SyncManager>, Arc<SyncManager>) {
+        let backend = Arc::new(InMemoryBackend::default());
+        let mut instances = Vec::new();
+        for _ in 0..2 {
+            let manager = Arc::new(SyncManager::with_backend(
+                pool.clone(),
+                SyncConfig::default(),
+                backend.clone(),
+            ));
+            manager.start_relay().await.unwrap();
+            instances.push(manager);
+        }
+        let second = instances.pop().unwrap();
+        (instances.pop().unwrap(), second)
+    }
+
+    #[sqlx::test]
+    async fn guids_of_other_vaults_are_neither_loaded_nor_overwritten(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let other_vault = create_vault(&pool, user_id).await;
+        let guid = "other-vault-doc";
+
+        let manager = SyncManager::new(pool.clone(), SyncConfig::default());
+        manager
+            .apply_update(
+                guid,
+                other_vault,
+                user_id,
+                &push_text(&Doc::new(), "secret"),
+            )
+            .await
+            .unwrap();
+        manager.flush(guid).await.unwrap();
+
+        // A fresh instance has no live copy to compare against, only the database
+        let fresh = SyncManager::new(pool.clone(), SyncConfig::default());
+        let Err(error) = fresh
+            .encode_diff(guid, vault_id, StateVector::default())
+            .await
+        else {
+            panic!("diff should be refused");
+        };
+        assert!(error.is::<DocumentNotFound>());
+        let Err(error) = fresh
+            .apply_update(guid, vault_id, user_id, &push_text(&Doc::new(), "mine"))
+            .await
+        else {
+            panic!("update should be refused");
+        };
+        assert!(error.is::<DocumentNotFound>());
+
+        // A save that slipped past the lookup still leaves the other vault's row alone
+        let error =
+            persistence::save_document(&pool, guid, &Doc::new(), vault_id, None, "document", None)
+                .await
+                .expect_err("save should be refused");
+        assert!(error.is::<DocumentNotFound>());
+        assert_eq!(live_text(&fresh, guid, other_vault).await, "secret");
+    }
+
+    #[sqlx::test]
+    async fn updates_reach_subscribers_on_other_instances(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let (first, second) = two_instances(&pool).await;
+        let guid = "shared-doc";
+
+        first.open_document(guid, vault_id).await.unwrap();
+        second.open_document(guid, vault_id).await.unwrap();
+        let mut remote_subscriber = second.subscribe(guid).await;
+        let _local_subscriber = first.subscribe(guid).await;
+
+        let update = push_text(&Doc::new(), "from the first instance");
+        let session = Uuid::new_v4();
+        first
+            .apply_update(guid, vault_id, user_id, &update)
+            .await
+            .unwrap();
+        first.publish_update(guid, session, &update).await.unwrap();
+
+        // The answer to the second instance's `Opened` event may arrive first
+        let relayed = tokio::time::timeout(tokio::time::Duration::from_secs(1), async {
+            loop {
+                let message = remote_subscriber.recv().await.unwrap();
+                if message.origin.is_some() {
+                    break message;
+                }
+            }
+        })
+        .await
+        .expect("relayed update");
+        assert_eq!(relayed.origin, Some(session));
+        assert_eq!(
+            relayed.payload,
+            Message::Sync(SyncMessage::Update(update.clone())).encode_v1()
+        );
+        assert_eq!(
+            live_text(&second, guid, vault_id).await,
+            "from the first instance"
+        );
+    }
+
+    #[sqlx::test]
+    async fn presence_covers_sessions_on_every_instance(pool: PgPool) {
+        let (first, second) = two_instances(&pool).await;
+        let vault_id = Uuid::new_v4();
+        let (local_user, remote_user) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let _local = first.track_session(Uuid::new_v4(), local_user, vault_id);
+        let remote_session = Uuid::new_v4();
+        let remote = second.track_session(remote_session, remote_user, vault_id);
+        second.set_document_presence(remote_session, "shared-doc", true);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        for manager in [&first, &second] {
+            let presence = manager.vault_presence(vault_id);
+            assert_eq!(
+                presence.user_ids,
+                [local_user, remote_user].into_iter().collect()
+            );
+            assert_eq!(
+                presence.documents.get("shared-doc"),
+                Some(&[remote_user].into_iter().collect())
+            );
+        }
+
+        drop(remote);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let presence = first.vault_presence(vault_id);
+        assert_eq!(presence.user_ids, [local_user].into_iter().collect());
+        assert!(presence.documents.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn opening_a_document_pulls_unflushed_edits_from_other_instances(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let (first, second) = two_instances(&pool).await;
+        let guid = "handover-doc";
+
+        // Persisted straight away because the document is new
+        let doc = Doc::new();
+        first
+            .apply_update(guid, vault_id, user_id, &push_text(&doc, "saved "))
+            .await
+            .unwrap();
+        // Only in the first instance's memory until the debounce fires
+        first
+            .apply_update(guid, vault_id, user_id, &push_text(&doc, "pending"))
+            .await
+            .unwrap();
+
+        second.open_document(guid, vault_id).await.unwrap();
+        let mut content = String::new();
+        for _ in 0..20 {
+            content = live_text(&second, guid, vault_id).await;
+            if content == "saved pending" {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(25)).await;
+        }
+        assert_eq!(content, "saved pending");
+    }
+
+    async fn logged_updates(pool: &PgPool, guid: &str) -> i64 {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM document_updates WHERE subdoc_guid = $1"#,
+            guid
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn flushes_append_to_the_log_until_compacted(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let manager = SyncManager::new(pool.clone(), SyncConfig::default());
+        let guid = "logged-doc";
+
+        let doc = Doc::new();
+        for part in ["one ", "two ", "three"] {
+            manager
+                .apply_update(guid, vault_id, user_id, &push_text(&doc, part))
+                .await
+                .unwrap();
+            manager.flush(guid).await.unwrap();
+        }
+
+        // The first update created the row; the others were logged
+        assert_eq!(logged_updates(&pool, guid).await, 2);
+
+        let merged = persistence::compact_document(&pool, guid).await.unwrap();
+        assert_eq!(merged, 2);
+        assert_eq!(logged_updates(&pool, guid).await, 0);
+
+        let doc = persistence::load_or_create_document(&pool, guid, vault_id)
+            .await
+            .unwrap()
+            .doc;
+        let text = doc.get_or_insert_text("content");
+        assert_eq!(text.get_string(&doc.transact()), "one two three");
+    }
+
+    #[sqlx::test]
+    async fn idle_documents_are_evicted_after_the_grace_period(pool: PgPool) {
+        let user_id = create_user(&pool).await;
+        let vault_id = create_vault(&pool, user_id).await;
+        let config = SyncConfig {
+            idle_eviction_ms: 200,
+            ..SyncConfig::default()
+        };
+        let manager = Arc::new(SyncManager::new(pool.clone(), config));
+        let _eviction = manager.start_eviction();
+
+        let idle = manager.subscribe("idle-doc").await;
+        let _busy = manager.subscribe("busy-doc").await;
+        for guid in ["idle-doc", "busy-doc"] {
+            manager
+                .apply_update(guid, vault_id, user_id, &push_text(&Doc::new(), guid))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            manager.active_documents().await,
+            vec![
+                ActiveDocument {
+                    guid: "busy-doc".to_string(),
+                    subscribers: 1,
+                    loaded: true,
+                },
+                ActiveDocument {
+                    guid: "idle-doc".to_string(),
+                    subscribers: 1,
+                    loaded: true,
+                },
+            ]
+        );
+
+        drop(idle);
+        manager.release("idle-doc").await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(600)).await;
+
+        let active = manager.active_documents().await;
+        assert_eq!(
+            active
+                .iter()
+                .map(|doc| doc.guid.as_str())
+                .collect::<Vec<_>>(),
+            vec!["busy-doc"]
+        );
+        // Reopening loads the flushed state again
+        assert_eq!(live_text(&manager, "idle-doc", vault_id).await, "idle-doc");
     }
 }
@@ -0,0 +1,629 @@
+use super::{DocumentDeleted, DocumentNotFound, InvalidMove};
+use crate::models::{DocCreationPolicy, DocumentMetadata, DocumentMetadataPatch};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::{Doc, ReadTxn, StateVector, Transact, Update};
+
+/// A document as the sync server holds it
+pub struct LoadedDocument {
+    pub doc: Doc,
+    pub metadata: DocumentMetadata,
+    /// Whether the document has a `subdocs` row yet
+    pub persisted: bool,
+    /// Whether the document is in the trash
+    pub deleted: bool,
+}
+
+// Load document from database, or create new one if doesn't exist
+// Verifies document belongs to the specified vault; a guid taken by another vault
+// is DocumentNotFound rather than a fresh document, and so is any unknown guid
+// when the vault's creation policy is to reject them
+// The stored state is the compacted base plus every update logged since
+// Documents in the trash are loaded too, flagged as deleted, so that they are
+// never mistaken for unknown guids
+pub async fn load_or_create_document(
+    pool: &PgPool,
+    guid: &str,
+    vault_id: Uuid,
+) -> anyhow::Result<LoadedDocument> {
+    // The base and the log are read from one snapshot: a compaction committing between
+    // the two reads would otherwise leave the old base with an emptied log
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *tx)
+        .await?;
+
+    // Try to load from database, verifying it belongs to this vault
+    let result = sqlx::query!(
+        r#"
+        SELECT
+            s.yjs_state,
+            s.created_at,
+            s.modified_at,
+            s.parent_guid,
+            s.doc_type,
+            m.title,
+            m.icon,
+            m.description,
+            COALESCE(m.tags, '{}') as tags,
+            COALESCE(m.extra, '{}') as "extra!",
+            s.deleted_at IS NOT NULL as "deleted!"
+        FROM subdocs s
+        LEFT JOIN subdoc_metadata m ON s.guid = m.subdoc_guid
+        WHERE s.guid = $1 AND s.vault_id = $2
+        "#,
+        guid,
+        vault_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(record) = result {
+        // Document exists and belongs to this vault, load it
+        let doc = Doc::new();
+        let update = Update::decode_v1(&record.yjs_state)?;
+        {
+            let mut txn = doc.transact_mut();
+            txn.apply_update(update)?;
+        }
+
+        // Replay updates that have not been compacted into yjs_state yet
+        let pending = sqlx::query_scalar!(
+            r#"SELECT update FROM document_updates WHERE subdoc_guid = $1 ORDER BY id"#,
+            guid
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        if !pending.is_empty() {
+            let mut txn = doc.transact_mut();
+            for update in &pending {
+                txn.apply_update(Update::decode_v1(update)?)?;
+            }
+        }
+
+        let tags = record.tags.unwrap_or_default();
+
+        let metadata = DocumentMetadata {
+            guid: guid.to_string(),
+            vault_id,
+            title: record.title,
+            doc_type: record.doc_type,
+            icon: record.icon,
+            description: record.description,
+            tags,
+            extra: record.extra,
+            parent_guid: record.parent_guid,
+            created_at: record.created_at,
+            modified_at: record.modified_at,
+        };
+
+        tracing::debug!(
+            "Loaded {}document {} from vault {} ({} logged updates)",
+            if record.deleted { "deleted " } else { "" },
+            guid,
+            vault_id,
+            pending.len()
+        );
+        Ok(LoadedDocument {
+            doc,
+            metadata,
+            persisted: true,
+            deleted: record.deleted,
+        })
+    } else {
+        let owner = sqlx::query_scalar!("SELECT vault_id FROM subdocs WHERE guid = $1", guid)
+            .fetch_optional(pool)
+            .await?;
+        if owner.is_some_and(|owner| owner != vault_id) {
+            tracing::warn!(
+                "Document {} requested for vault {} belongs to another vault",
+                guid,
+                vault_id
+            );
+            return Err(DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            }
+            .into());
+        }
+
+        let vault = sqlx::query!(
+            r#"
+            SELECT doc_creation_policy, auto_create_doc_type, auto_create_parent_guid
+            FROM vaults
+            WHERE id = $1
+            "#,
+            vault_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        let policy = vault.map(|vault| {
+            DocCreationPolicy::from_columns(
+                &vault.doc_creation_policy,
+                vault.auto_create_doc_type,
+                vault.auto_create_parent_guid,
+            )
+        });
+        let Some(DocCreationPolicy::AutoCreate {
+            doc_type,
+            parent_guid,
+        }) = policy
+        else {
+            tracing::info!(
+                "Refusing unknown document {} in vault {} (doc creation policy: reject)",
+                guid,
+                vault_id
+            );
+            return Err(DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            }
+            .into());
+        };
+
+        // Document doesn't exist, create new one as the vault's policy declares
+        let doc = Doc::new();
+        let now = Utc::now();
+
+        let metadata = DocumentMetadata {
+            guid: guid.to_string(),
+            vault_id,
+            title: "Untitled".to_string(),
+            doc_type,
+            icon: None,
+            description: None,
+            tags: vec![],
+            extra: serde_json::json!({}),
+            parent_guid,
+            created_at: now,
+            modified_at: now,
+        };
+
+        tracing::debug!("Created new document {} for vault {}", guid, vault_id);
+        Ok(LoadedDocument {
+            doc,
+            metadata,
+            persisted: false,
+            deleted: false,
+        })
+    }
+}
+
+// Save the full document state, creating the row if needed
+// Used for new documents; existing ones append to the update log instead
+// Never touches a row of another vault (DocumentNotFound) or one in the trash
+// (DocumentDeleted)
+pub async fn save_document(
+    pool: &PgPool,
+    guid: &str,
+    doc: &Doc,
+    vault_id: Uuid,
+    user_id: Option<Uuid>,
+    doc_type: &str,
+    parent_guid: Option<&str>,
+) -> anyhow::Result<()> {
+    tracing::debug!(
+        "save_document called for guid: {}, vault_id: {}, user_id: {:?}, doc_type: {}, parent_guid: {:?}",
+        guid,
+        vault_id,
+        user_id,
+        doc_type,
+        parent_guid
+    );
+
+    let (state_bytes, state_vector_bytes) = {
+        let txn = doc.transact();
+        let state_bytes = txn.encode_state_as_update_v1(&StateVector::default());
+        let state_vector_bytes = txn.state_vector().encode_v1();
+        tracing::debug!(
+            "Encoded state: {} bytes, state_vector: {} bytes",
+            state_bytes.len(),
+            state_vector_bytes.len()
+        );
+        (state_bytes, state_vector_bytes)
+    };
+
+    // Insert or update document
+    tracing::debug!(
+        "Executing INSERT OR UPDATE query for guid: {} with vault_id: {}",
+        guid,
+        vault_id
+    );
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO subdocs (guid, vault_id, doc_type, parent_guid, yjs_state, state_vector, modified_at)
+        VALUES ($1, $2, $3, $6, $4, $5, NOW())
+        ON CONFLICT (guid) DO UPDATE
+        SET yjs_state = $4, state_vector = $5, modified_at = NOW()
+        WHERE subdocs.vault_id = EXCLUDED.vault_id AND subdocs.deleted_at IS NULL
+        "#,
+        guid,
+        vault_id,
+        doc_type,
+        state_bytes,
+        state_vector_bytes,
+        parent_guid,
+    )
+    .execute(pool)
+    .await?;
+
+    // The guid was taken by another vault, or trashed, in the meantime
+    if result.rows_affected() == 0 {
+        let owner = sqlx::query_scalar!("SELECT vault_id FROM subdocs WHERE guid = $1", guid)
+            .fetch_optional(pool)
+            .await?;
+        return Err(if owner == Some(vault_id) {
+            DocumentDeleted {
+                guid: guid.to_string(),
+            }
+            .into()
+        } else {
+            DocumentNotFound {
+                guid: guid.to_string(),
+                vault_id,
+            }
+            .into()
+        });
+    }
+
+    // Ensure metadata record exists (insert default metadata if it doesn't)
+    sqlx::query!(
+        r#"
+        INSERT INTO subdoc_metadata (subdoc_guid, title, modified_at)
+        VALUES ($1, 'Untitled', NOW())
+        ON CONFLICT (subdoc_guid) DO UPDATE
+        SET modified_at = NOW()
+        "#,
+        guid
+    )
+    .execute(pool)
+    .await?;
+
+    tracing::info!(
+        "Saved document {} to vault {} by user {:?} (rows affected: {})",
+        guid,
+        vault_id,
+        user_id,
+        result.rows_affected()
+    );
+    Ok(())
+}
+
+// Apply a metadata patch to a saved document of the vault
+// Returns the resulting metadata, or None if the vault has no such (saved) document
+pub async fn update_metadata(
+    pool: &PgPool,
+    guid: &str,
+    vault_id: Uuid,
+    patch: &DocumentMetadataPatch,
+) -> anyhow::Result<Option<DocumentMetadata>> {
+    let record = sqlx::query!(
+        r#"
+        UPDATE subdoc_metadata m
+        SET
+            title = COALESCE($3, m.title),
+            icon = CASE WHEN $4 THEN $5 ELSE m.icon END,
+            description = CASE WHEN $6 THEN $7 ELSE m.description END,
+            tags = COALESCE($8, m.tags),
+            extra = COALESCE($9, m.extra),
+            modified_at = NOW()
+        FROM subdocs s
+        WHERE m.subdoc_guid = $1
+            AND s.guid = m.subdoc_guid
+            AND s.vault_id = $2
+            AND s.deleted_at IS NULL
+        RETURNING
+            m.title,
+            m.icon,
+            m.description,
+            COALESCE(m.tags, '{}') as tags,
+            COALESCE(m.extra, '{}') as "extra!",
+            s.doc_type,
+            s.parent_guid,
+            s.created_at,
+            m.modified_at
+        "#,
+        guid,
+        vault_id,
+        patch.title,
+        patch.icon.is_some(),
+        patch.icon.clone().flatten(),
+        patch.description.is_some(),
+        patch.description.clone().flatten(),
+        patch.tags.as_deref(),
+        patch.extra.clone().map(serde_json::Value::Object),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.map(|record| DocumentMetadata {
+        guid: guid.to_string(),
+        vault_id,
+        title: record.title,
+        doc_type: record.doc_type,
+        icon: record.icon,
+        description: record.description,
+        tags: record.tags.unwrap_or_default(),
+        extra: record.extra,
+        parent_guid: record.parent_guid,
+        created_at: record.created_at,
+        modified_at: record.modified_at,
+    }))
+}
+
+// Set the parent of a live document of the vault
+// Returns the resulting metadata, or None if the vault has no such (saved) document
+// A parent that is not a live document of the vault, or that would make a cycle, is InvalidMove
+pub async fn move_document(
+    pool: &PgPool,
+    guid: &str,
+    vault_id: Uuid,
+    parent_guid: Option<&str>,
+) -> anyhow::Result<Option<DocumentMetadata>> {
+    let mut tx = pool.begin().await?;
+
+    // Moves and document deletions within a vault run one at a time, so two moves cannot
+    // each pass the cycle check and together link documents into a loop, and no document
+    // moves under a tree while it goes to the trash
+    sqlx::query!(
+        "SELECT id FROM vaults WHERE id = $1 FOR NO KEY UPDATE",
+        vault_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(parent_guid) = parent_guid {
+        // The parent row stays locked, so it cannot go to the trash before the move commits
+        let parent = sqlx::query_scalar!(
+            r#"
+            SELECT guid FROM subdocs
+            WHERE guid = $1 AND vault_id = $2 AND deleted_at IS NULL
+            FOR SHARE
+            "#,
+            parent_guid,
+            vault_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if parent.is_none() {
+            return Err(InvalidMove::ParentNotFound(parent_guid.to_string()).into());
+        }
+
+        let cycle = sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE ancestors AS (
+                SELECT guid, parent_guid FROM subdocs WHERE guid = $1
+                UNION
+                SELECT s.guid, s.parent_guid FROM subdocs s
+                JOIN ancestors a ON s.guid = a.parent_guid
+            )
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE guid = $2) AS "cycle!"
+            "#,
+            parent_guid,
+            guid
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if cycle {
+            return Err(InvalidMove::Cycle {
+                guid: guid.to_string(),
+                parent_guid: parent_guid.to_string(),
+            }
+            .into());
+        }
+    }
+
+    let record = sqlx::query!(
+        r#"
+        UPDATE subdocs s
+        SET parent_guid = $3, modified_at = NOW()
+        FROM subdoc_metadata m
+        WHERE s.guid = $1
+            AND s.vault_id = $2
+            AND s.deleted_at IS NULL
+            AND m.subdoc_guid = s.guid
+        RETURNING
+            m.title,
+            m.icon,
+            m.description,
+            COALESCE(m.tags, '{}') as tags,
+            COALESCE(m.extra, '{}') as "extra!",
+            s.doc_type,
+            s.parent_guid,
+            s.created_at,
+            s.modified_at
+        "#,
+        guid,
+        vault_id,
+        parent_guid,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(record.map(|record| DocumentMetadata {
+        guid: guid.to_string(),
+        vault_id,
+        title: record.title,
+        doc_type: record.doc_type,
+        icon: record.icon,
+        description: record.description,
+        tags: record.tags.unwrap_or_default(),
+        extra: record.extra,
+        parent_guid: record.parent_guid,
+        created_at: record.created_at,
+        modified_at: record.modified_at,
+    }))
+}
+
+// Take a document of the vault out of the trash
+// Returns whether it was in the trash
+pub async fn restore_document(pool: &PgPool, guid: &str, vault_id: Uuid) -> anyhow::Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE subdocs
+        SET deleted_at = NULL, deleted_by = NULL
+        WHERE guid = $1 AND vault_id = $2 AND deleted_at IS NOT NULL
+        "#,
+        guid,
+        vault_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Append an update to the document's log; cheap compared to rewriting the full state
+// Documents in the trash take no updates: that is DocumentDeleted
+pub async fn append_update(
+    pool: &PgPool,
+    guid: &str,
+    user_id: Option<Uuid>,
+    update: &[u8],
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO document_updates (subdoc_guid, user_id, update)
+        SELECT $1, $2, $3
+        WHERE EXISTS (SELECT 1 FROM subdocs WHERE guid = $1 AND deleted_at IS NULL)
+        "#,
+        guid,
+        user_id,
+        update
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(DocumentDeleted {
+            guid: guid.to_string(),
+        }
+        .into());
+    }
+
+    sqlx::query!(
+        "UPDATE subdocs SET modified_at = NOW() WHERE guid = $1",
+        guid
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE subdoc_metadata SET modified_at = NOW() WHERE subdoc_guid = $1",
+        guid
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    tracing::debug!(
+        "Appended {} byte update to document {} by user {:?}",
+        update.len(),
+        guid,
+        user_id
+    );
+    Ok(())
+}
+
+// Documents whose update log has grown past either threshold
+pub async fn documents_needing_compaction(
+    pool: &PgPool,
+    max_updates: i64,
+    max_bytes: i64,
+) -> anyhow::Result<Vec<String>> {
+    let guids = sqlx::query_scalar!(
+        r#"
+        SELECT subdoc_guid
+        FROM document_updates
+        GROUP BY subdoc_guid
+        HAVING COUNT(*) >= $1 OR SUM(octet_length(update)) >= $2
+        "#,
+        max_updates,
+        max_bytes
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(guids)
+}
+
+// Merge a document's logged updates into subdocs.yjs_state and drop them from the log
+// Returns how many log entries were merged
+pub async fn compact_document(pool: &PgPool, guid: &str) -> anyhow::Result<usize> {
+    let mut tx = pool.begin().await?;
+
+    // Serializes compactors. Appends wait for the commit too: they bump the row's modified_at
+    let Some(base) = sqlx::query_scalar!(
+        "SELECT yjs_state FROM subdocs WHERE guid = $1 FOR NO KEY UPDATE",
+        guid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(0);
+    };
+
+    let entries = sqlx::query!(
+        "SELECT id, update FROM document_updates WHERE subdoc_guid = $1 ORDER BY id",
+        guid
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    let doc = Doc::new();
+    {
+        let mut txn = doc.transact_mut();
+        txn.apply_update(Update::decode_v1(&base)?)?;
+        for entry in &entries {
+            txn.apply_update(Update::decode_v1(&entry.update)?)?;
+        }
+    }
+    let (state_bytes, state_vector_bytes) = {
+        let txn = doc.transact();
+        (
+            txn.encode_state_as_update_v1(&StateVector::default()),
+            txn.state_vector().encode_v1(),
+        )
+    };
+
+    sqlx::query!(
+        "UPDATE subdocs SET yjs_state = $2, state_vector = $3 WHERE guid = $1",
+        guid,
+        state_bytes,
+        state_vector_bytes
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Delete exactly the rows merged above; appends committed meanwhile stay in the log
+    let merged_ids: Vec<i64> = entries.iter().map(|entry| entry.id).collect();
+    sqlx::query!(
+        "DELETE FROM document_updates WHERE id = ANY($1)",
+        &merged_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    tracing::info!(
+        "Compacted {} logged updates into document {} ({} bytes)",
+        merged_ids.len(),
+        guid,
+        state_bytes.len()
+    );
+    Ok(merged_ids.len())
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How often every instance announces everyone connected through it, so that new
+/// instances catch up and the presence of instances that went away expires
+pub const PRESENCE_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Presence announced by an instance that has not been heard from for this long is
+/// ignored; the instance most likely went away without saying so
+const PRESENCE_EXPIRY: Duration = Duration::from_secs(90);
+
+struct SessionPresence {
+    user_id: Uuid,
+    vault_id: Uuid,
+    /// Documents the session is subscribed to
+    documents: HashSet<String>,
+}
+
+/// Users connected to a vault through any instance, and who has which document open
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VaultPresence {
+    pub user_ids: BTreeSet<Uuid>,
+    pub documents: BTreeMap<String, BTreeSet<Uuid>>,
+}
+
+impl VaultPresence {
+    fn add(&mut self, user_id: Uuid, documents: impl IntoIterator<Item = String>) {
+        self.user_ids.insert(user_id);
+        for guid in documents {
+            self.documents.entry(guid).or_default().insert(user_id);
+        }
+    }
+}
+
+/// The sessions one instance has open in a vault, as announced to the other instances
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceSnapshot {
+    pub vault_id: Uuid,
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub user_id: Uuid,
+    pub documents: Vec<String>,
+}
+
+struct RemotePresence {
+    sessions: Vec<SessionSnapshot>,
+    received: Instant,
+}
+
+#[derive(Default)]
+struct PresenceState {
+    /// Sessions on this instance, keyed by WebSocket session
+    local: HashMap<Uuid, SessionPresence>,
+    /// Latest snapshot per (instance, vault) from the other instances
+    remote: HashMap<(Uuid, Uuid), RemotePresence>,
+    /// Vaults whose local sessions changed since they were last announced
+    changed: HashSet<Uuid>,
+}
+
+/// Who is connected to which vault and document, on this instance and the others
+#[derive(Default)]
+pub struct Presence {
+    state: Mutex<PresenceState>,
+    /// Woken when local presence changes and has to be announced
+    changed: Notify,
+}
+
+impl Presence {
+    pub fn join(&self, session_id: Uuid, user_id: Uuid, vault_id: Uuid) {
+        self.lock().local.insert(
+            session_id,
+            SessionPresence {
+                user_id,
+                vault_id,
+                documents: HashSet::new(),
+            },
+        );
+        self.mark_changed(vault_id);
+    }
+
+    pub fn leave(&self, session_id: Uuid) {
+        let session = self.lock().local.remove(&session_id);
+        if let Some(session) = session {
+            self.mark_changed(session.vault_id);
+        }
+    }
+
+    pub fn open_document(&self, session_id: Uuid, guid: &str) {
+        let vault_id = self.lock().local.get_mut(&session_id).map(|session| {
+            session.documents.insert(guid.to_string());
+            session.vault_id
+        });
+        if let Some(vault_id) = vault_id {
+            self.mark_changed(vault_id);
+        }
+    }
+
+    pub fn close_document(&self, session_id: Uuid, guid: &str) {
+        let vault_id = self.lock().local.get_mut(&session_id).map(|session| {
+            session.documents.remove(guid);
+            session.vault_id
+        });
+        if let Some(vault_id) = vault_id {
+            self.mark_changed(vault_id);
+        }
+    }
+
+    pub fn vault(&self, vault_id: Uuid) -> VaultPresence {
+        let state = self.lock();
+        let mut presence = VaultPresence::default();
+        for session in state.local.values() {
+            if session.vault_id == vault_id {
+                presence.add(session.user_id, session.documents.iter().cloned());
+            }
+        }
+        for ((_, remote_vault), remote) in &state.remote {
+            if *remote_vault != vault_id || remote.received.elapsed() >= PRESENCE_EXPIRY {
+                continue;
+            }
+            for session in &remote.sessions {
+                presence.add(session.user_id, session.documents.iter().cloned());
+            }
+        }
+        presence
+    }
+
+    /// Take in a snapshot announced by another instance, replacing its previous one
+    pub fn apply_remote(&self, instance_id: Uuid, snapshot: PresenceSnapshot) {
+        let mut state = self.lock();
+        let key = (instance_id, snapshot.vault_id);
+        if snapshot.sessions.is_empty() {
+            state.remote.remove(&key);
+        } else {
+            state.remote.insert(
+                key,
+                RemotePresence {
+                    sessions: snapshot.sessions,
+                    received: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Resolves once local presence has changed since the last `take_changed`
+    pub async fn wait_for_change(&self) {
+        self.changed.notified().await
+    }
+
+    /// Snapshots of the vaults whose local sessions changed; empty ones tell the other
+    /// instances that nobody is left
+    pub fn take_changed(&self) -> Vec<PresenceSnapshot> {
+        let mut state = self.lock();
+        let changed = std::mem::take(&mut state.changed);
+        changed
+            .into_iter()
+            .map(|vault_id| snapshot(&state.local, vault_id))
+            .collect()
+    }
+
+    /// Snapshots of every vault with local sessions, for the heartbeat; expired
+    /// remote presence is dropped on the way
+    pub fn take_all(&self) -> Vec<PresenceSnapshot> {
+        let mut state = self.lock();
+        state
+            .remote
+            .retain(|_, remote| remote.received.elapsed() < PRESENCE_EXPIRY);
+        let mut vaults = std::mem::take(&mut state.changed);
+        vaults.extend(state.local.values().map(|session| session.vault_id));
+        vaults
+            .into_iter()
+            .map(|vault_id| snapshot(&state.local, vault_id))
+            .collect()
+    }
+
+    fn mark_changed(&self, vault_id: Uuid) {
+        self.lock().changed.insert(vault_id);
+        self.changed.notify_one();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PresenceState> {
+        // Every update leaves the state consistent, so a panic elsewhere does not poison it
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn snapshot(local: &HashMap<Uuid, SessionPresence>, vault_id: Uuid) -> PresenceSnapshot {
+    PresenceSnapshot {
+        vault_id,
+        sessions: local
+            .values()
+            .filter(|session| session.vault_id == vault_id)
+            .map(|session| SessionSnapshot {
+                user_id: session.user_id,
+                documents: session.documents.iter().cloned().collect(),
+            })
+            .collect(),
+    }
+}
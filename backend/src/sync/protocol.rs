@@ -0,0 +1,396 @@
+//! y-protocols messages shared by the WebSocket handler and cross-instance relay
+//!
+//! Frames carry one or more [`yrs::sync::Message`]s back to back. The standard kinds
+//! are encoded by yrs:
+//! - `0` sync: step 1 (state vector), step 2 and update
+//! - `1` awareness
+//! - `2` auth: server → client permission denied
+//! - `3` awareness query
+//!
+//! This server's own messages are [`Extension`]s, sent as custom messages whose
+//! payload is varString(json):
+//! - `4` control: connection-level [`ControlMessage`]s
+//! - `5` error: server → client [`ErrorFrame`]
+//! - `6` metadata: server → client `DocumentMetadata`
+//! - `7` metadata patch: client → server `DocumentMetadataPatch`
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use yrs::encoding::read;
+use yrs::sync::Message;
+use yrs::updates::decoder::{Decode, Decoder, DecoderV1};
+use yrs::updates::encoder::Encode;
+
+pub const MSG_CONTROL: u8 = 4;
+pub const MSG_ERROR: u8 = 5;
+pub const MSG_METADATA: u8 = 6;
+pub const MSG_METADATA_PATCH: u8 = 7;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("message ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("varUint does not fit in 32 bits")]
+    VarUintOverflow,
+    #[error("invalid message: {0}")]
+    Invalid(String),
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("string is not valid utf-8")]
+    InvalidUtf8,
+    #[error("invalid {0} message: {1}")]
+    InvalidJson(&'static str, String),
+    #[error("document guid of {size} bytes exceeds the limit of {max} bytes")]
+    GuidTooLong { size: usize, max: usize },
+    #[error("only control messages may be addressed to the empty guid")]
+    MissingGuid,
+}
+
+impl From<read::Error> for ProtocolError {
+    fn from(error: read::Error) -> Self {
+        match error {
+            read::Error::EndOfBuffer(_) => ProtocolError::UnexpectedEnd,
+            other => ProtocolError::Invalid(other.to_string()),
+        }
+    }
+}
+
+/// Control messages. Connection-level ones are addressed to the empty guid on
+/// multiplexed connections; document-level ones to the document they are about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Client → server: authenticate, or re-authenticate with a fresh access token
+    Auth { token: String },
+    /// Server → client: the token was accepted and is valid until `expires_at` (unix seconds)
+    Authenticated { expires_at: i64 },
+    /// Server → client: a re-auth token was rejected; the previous one stays in effect
+    AuthFailed { reason: String },
+    /// Server → client: the session is closed at `expires_at` unless the client re-authenticates
+    TokenExpiring { expires_at: i64 },
+    /// Server → client: the document was moved to the trash; further updates are refused
+    DocDeleted,
+    /// Server → client: the document was restored from the trash and takes updates again
+    DocRestored,
+}
+
+/// Why the server refused a client message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The user may only read the document
+    ReadOnly,
+    /// The document does not exist in the connection's vault
+    DocNotFound,
+    /// The document is in the trash and takes no updates
+    DocDeleted,
+    /// The message could not be decoded
+    Malformed,
+    /// The message or the document it would produce exceeds a size limit
+    TooLarge,
+    /// The client sends updates faster than it is allowed to
+    RateLimited,
+}
+
+/// Server → client notice that a message was refused. Refused edits are followed by a
+/// sync step 2 carrying the server's state, so the client can drop what it applied locally.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorFrame {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Message kinds this server adds on top of y-protocols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extension {
+    /// JSON `DocumentMetadata`
+    Metadata(String),
+    /// JSON `DocumentMetadataPatch`
+    MetadataPatch(String),
+    Control(ControlMessage),
+    Error(ErrorFrame),
+}
+
+impl Extension {
+    /// Parse the payload of a custom message
+    pub fn from_custom(tag: u8, data: &[u8]) -> Result<Self, ProtocolError> {
+        let json = std::str::from_utf8(data).map_err(|_| ProtocolError::InvalidUtf8)?;
+        match tag {
+            MSG_METADATA => Ok(Extension::Metadata(json.to_string())),
+            MSG_METADATA_PATCH => Ok(Extension::MetadataPatch(json.to_string())),
+            MSG_CONTROL => serde_json::from_str(json)
+                .map(Extension::Control)
+                .map_err(|e| ProtocolError::InvalidJson("control", e.to_string())),
+            MSG_ERROR => serde_json::from_str(json)
+                .map(Extension::Error)
+                .map_err(|e| ProtocolError::InvalidJson("error", e.to_string())),
+            other => Err(ProtocolError::UnknownMessageType(other)),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        let (tag, json) = match self {
+            Extension::Metadata(json) => (MSG_METADATA, json.clone()),
+            Extension::MetadataPatch(json) => (MSG_METADATA_PATCH, json.clone()),
+            Extension::Control(control) => (
+                MSG_CONTROL,
+                serde_json::to_string(control).expect("control messages always serialize to JSON"),
+            ),
+            Extension::Error(error) => (
+                MSG_ERROR,
+                serde_json::to_string(error).expect("error frames always serialize to JSON"),
+            ),
+        };
+        Message::Custom(tag, json.into_bytes())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_message().encode_v1()
+    }
+}
+
+/// Decode every message in a frame. A message cut short is an error rather than
+/// being dropped, and so is an empty frame.
+pub fn decode_frame(data: &[u8]) -> Result<Vec<Message>, ProtocolError> {
+    if data.is_empty() {
+        return Err(ProtocolError::UnexpectedEnd);
+    }
+
+    let mut messages = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let mut decoder = DecoderV1::from(rest);
+        messages.push(Message::decode(&mut decoder)?);
+        let remaining = decoder.read_to_end()?.len();
+        rest = &rest[rest.len() - remaining..];
+    }
+    Ok(messages)
+}
+
+/// Read a lib0 varUint, rejecting values that do not fit in 32 bits
+fn read_var_uint(data: &[u8]) -> Result<(u32, &[u8]), ProtocolError> {
+    let mut value: u32 = 0;
+
+    for (pos, &byte) in data.iter().enumerate() {
+        let shift = 7 * pos as u32;
+        let bits = (byte & 0x7F) as u32;
+        // The fifth byte only has room for the top 4 bits
+        if shift >= 32 || (shift == 28 && bits > 0x0F) {
+            return Err(ProtocolError::VarUintOverflow);
+        }
+        value |= bits << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, &data[pos + 1..]));
+        }
+    }
+
+    Err(ProtocolError::UnexpectedEnd)
+}
+
+/// Read a lib0 varByteArray (varUint length • bytes)
+fn read_var_bytes(data: &[u8]) -> Result<(&[u8], &[u8]), ProtocolError> {
+    let (len, rest) = read_var_uint(data)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return Err(ProtocolError::UnexpectedEnd);
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Longest document guid a client may address, in bytes
+pub const MAX_GUID_BYTES: usize = 256;
+
+/// Read the guid a multiplexed frame is addressed to, followed by the rest of the frame
+pub fn read_guid(data: &[u8]) -> Result<(&str, &[u8]), ProtocolError> {
+    let (guid, rest) = read_var_string(data)?;
+    if guid.len() > MAX_GUID_BYTES {
+        return Err(ProtocolError::GuidTooLong {
+            size: guid.len(),
+            max: MAX_GUID_BYTES,
+        });
+    }
+    Ok((guid, rest))
+}
+
+/// Read a lib0 varString (varUint length • utf-8 bytes)
+pub fn read_var_string(data: &[u8]) -> Result<(&str, &[u8]), ProtocolError> {
+    let (bytes, rest) = read_var_bytes(data)?;
+    let string = std::str::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8)?;
+    Ok((string, rest))
+}
+
+fn write_var_uint(buf: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn write_var_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_var_uint(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn write_var_string(buf: &mut Vec<u8>, string: &str) {
+    write_var_bytes(buf, string.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use yrs::StateVector;
+    use yrs::sync::SyncMessage;
+    use yrs::sync::awareness::{AwarenessUpdate, AwarenessUpdateEntry};
+
+    fn control_message() -> impl Strategy<Value = ControlMessage> {
+        prop_oneof![
+            any::<String>().prop_map(|token| ControlMessage::Auth { token }),
+            any::<i64>().prop_map(|expires_at| ControlMessage::Authenticated { expires_at }),
+            any::<String>().prop_map(|reason| ControlMessage::AuthFailed { reason }),
+            any::<i64>().prop_map(|expires_at| ControlMessage::TokenExpiring { expires_at }),
+            Just(ControlMessage::DocDeleted),
+            Just(ControlMessage::DocRestored),
+        ]
+    }
+
+    fn error_frame() -> impl Strategy<Value = ErrorFrame> {
+        let code = prop_oneof![
+            Just(ErrorCode::ReadOnly),
+            Just(ErrorCode::DocNotFound),
+            Just(ErrorCode::DocDeleted),
+            Just(ErrorCode::Malformed),
+            Just(ErrorCode::TooLarge),
+            Just(ErrorCode::RateLimited),
+        ];
+        (code, any::<String>()).prop_map(|(code, message)| ErrorFrame { code, message })
+    }
+
+    fn extension() -> impl Strategy<Value = Extension> {
+        prop_oneof![
+            any::<String>().prop_map(Extension::Metadata),
+            any::<String>().prop_map(Extension::MetadataPatch),
+            control_message().prop_map(Extension::Control),
+            error_frame().prop_map(Extension::Error),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        let bytes = proptest::collection::vec(any::<u8>(), 0..512);
+        let state_vector = proptest::collection::vec((any::<u64>(), 1..u32::MAX), 0..8)
+            .prop_map(StateVector::from_iter);
+        let awareness =
+            proptest::collection::hash_map(any::<u64>(), (any::<u32>(), any::<String>()), 0..4)
+                .prop_map(|clients| AwarenessUpdate {
+                    clients: clients
+                        .into_iter()
+                        .map(|(client, (clock, json))| {
+                            let json = serde_json::to_string(&json).unwrap().into();
+                            (client, AwarenessUpdateEntry { clock, json })
+                        })
+                        .collect(),
+                });
+        prop_oneof![
+            state_vector.prop_map(|sv| Message::Sync(SyncMessage::SyncStep1(sv))),
+            bytes
+                .clone()
+                .prop_map(|update| Message::Sync(SyncMessage::SyncStep2(update))),
+            bytes.prop_map(|update| Message::Sync(SyncMessage::Update(update))),
+            proptest::option::of(any::<String>()).prop_map(Message::Auth),
+            Just(Message::AwarenessQuery),
+            awareness.prop_map(Message::Awareness),
+            extension().prop_map(|extension| extension.to_message()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(message in message()) {
+            prop_assert_eq!(decode_frame(&message.encode_v1()), Ok(vec![message]));
+        }
+
+        #[test]
+        fn extensions_round_trip(extension in extension()) {
+            let decoded = decode_frame(&extension.encode())?;
+            let [Message::Custom(tag, data)] = &decoded[..] else {
+                panic!("expected one custom message, got {:?}", decoded);
+            };
+            prop_assert_eq!(Extension::from_custom(*tag, data), Ok(extension));
+        }
+
+        #[test]
+        fn frames_carry_several_messages(messages in proptest::collection::vec(message(), 1..4)) {
+            let frame: Vec<u8> = messages.iter().flat_map(|message| message.encode_v1()).collect();
+            prop_assert_eq!(decode_frame(&frame), Ok(messages));
+        }
+
+        #[test]
+        fn var_uints_round_trip(value in any::<u32>(), tail in proptest::collection::vec(any::<u8>(), 0..8)) {
+            let mut buf = Vec::new();
+            write_var_uint(&mut buf, value);
+            buf.extend_from_slice(&tail);
+            prop_assert_eq!(read_var_uint(&buf), Ok((value, &tail[..])));
+        }
+
+        #[test]
+        fn truncated_messages_are_rejected(message in message(), cut in any::<proptest::sample::Index>()) {
+            let encoded = message.encode_v1();
+            let truncated = &encoded[..cut.index(encoded.len())];
+            prop_assert!(decode_frame(truncated).is_err());
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_panic(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = decode_frame(&data);
+        }
+    }
+
+    #[test]
+    fn overlong_guids_are_rejected() {
+        let mut frame = Vec::new();
+        write_var_string(&mut frame, &"g".repeat(MAX_GUID_BYTES));
+        assert!(read_guid(&frame).is_ok());
+
+        let mut frame = Vec::new();
+        write_var_string(&mut frame, &"g".repeat(MAX_GUID_BYTES + 1));
+        assert_eq!(
+            read_guid(&frame),
+            Err(ProtocolError::GuidTooLong {
+                size: MAX_GUID_BYTES + 1,
+                max: MAX_GUID_BYTES
+            })
+        );
+    }
+
+    #[test]
+    fn var_uints_past_32_bits_are_rejected() {
+        assert_eq!(
+            read_var_uint(&[0xff, 0xff, 0xff, 0xff, 0x0f]),
+            Ok((u32::MAX, &[][..]))
+        );
+        assert_eq!(
+            read_var_uint(&[0xff, 0xff, 0xff, 0xff, 0x10]),
+            Err(ProtocolError::VarUintOverflow)
+        );
+        assert_eq!(
+            read_var_uint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]),
+            Err(ProtocolError::VarUintOverflow)
+        );
+    }
+
+    #[test]
+    fn unknown_custom_messages_are_rejected() {
+        assert_eq!(
+            Extension::from_custom(9, b"{}"),
+            Err(ProtocolError::UnknownMessageType(9))
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Fixtures shared by the database-backed tests
+
+use crate::api::auth::AppState;
+use crate::config::Config;
+use crate::storage::BlobStorage;
+use crate::storage::local::LocalStorage;
+use crate::sync::{SyncConfig, SyncManager};
+use axum::Router;
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Insert a user with a unique email and return its id
+pub async fn create_user(pool: &PgPool) -> Uuid {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO users (email, password_hash) VALUES ($1, 'not-a-real-hash') RETURNING id",
+    )
+    .bind(format!("{}@example.com", Uuid::new_v4()))
+    .fetch_one(pool)
+    .await
+    .expect("failed to create user")
+}
+
+/// Insert a user-owned vault and return its id
+pub async fn create_vault(pool: &PgPool, owner: Uuid) -> Uuid {
+    sqlx::query_scalar::<_, Uuid>(
+        "INSERT INTO vaults (id, user_id, vault_type, name) VALUES ($1, $2, 'user', 'Test vault') RETURNING id",
+    )
+    .bind(Uuid::new_v4())
+    .bind(owner)
+    .fetch_one(pool)
+    .await
+    .expect("failed to create vault")
+}
+
+pub const JWT_SECRET: &str = "test-secret";
+
+/// App state backed by `pool`, with uploads going to a throwaway directory
+pub fn app_state(pool: PgPool, sync: SyncConfig) -> AppState {
+    let config = Config {
+        database_url: String::new(),
+        port: 0,
+        jwt_secret: JWT_SECRET.to_string(),
+        metrics_token: None,
+        sync,
+    };
+    let sync_manager = Arc::new(SyncManager::new(pool.clone(), config.sync.clone()));
+    let storage = Arc::new(LocalStorage::new(
+        std::env::temp_dir().join(format!("just-type-test-{}", Uuid::new_v4())),
+        10 * 1024 * 1024,
+    )) as Arc<dyn BlobStorage>;
+
+    AppState {
+        pool,
+        config,
+        sync_manager,
+        storage,
+    }
+}
+
+/// Serve `router` on an ephemeral local port and return its address
+pub async fn serve(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind test listener");
+    let addr = listener.local_addr().expect("listener has no address");
+    tokio::spawn(async move {
+        axum::serve(listener, router)
+            .await
+            .expect("test server failed");
+    });
+    addr
+}
+
+/// Access token for `user_id` signed with [`JWT_SECRET`]
+pub fn token_for(user_id: Uuid) -> String {
+    crate::auth::generate_token(&user_id, JWT_SECRET).expect("failed to sign token")
+}